@@ -0,0 +1,103 @@
+// Counts heap allocations made while parsing, comparing tokens that borrow from the
+// source (lexer::lex) with tokens that own their text (lexer::Lexer over a reader).
+// Run with `cargo bench --bench allocations`
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use md_parser::ast::{Tree, run_ast};
+use md_parser::lexer::{Lexer, Token, lex};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Allocation count and bytes requested while running <f>
+fn measure<T>(f: impl FnOnce() -> T) -> (usize, usize, T) {
+    let (count, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        BYTES.load(Ordering::Relaxed),
+    );
+    let output: T = f();
+    (
+        ALLOCATIONS.load(Ordering::Relaxed) - count,
+        BYTES.load(Ordering::Relaxed) - bytes,
+        output,
+    )
+}
+
+fn sample() -> String {
+    let block: &str = "# Release notes\n\
+        \n\
+        Donec non massa quis est blandit volutpat. Donec sit amet.\n\
+        Nam vitae felis lectus. Sed sodales faucibus erat, a porttitor.\n\
+        \n\
+        Version|Date|Notes\n\
+        ---|---|---\n\
+        1.0|2024-01-01|First release\n\
+        1.1|2024-02-01|Bug fixes\n\
+        \n";
+    block.repeat(2_000)
+}
+
+fn main() {
+    let text: String = sample();
+
+    let (owned_lex, owned_lex_bytes, tokens) =
+        measure(|| Lexer::build(Cursor::new(text.as_bytes())).collect::<Vec<Token<'static>>>());
+    let (owned_ast, owned_ast_bytes, owned_tree) = measure(|| run_ast(tokens));
+
+    let (borrowed_lex, borrowed_lex_bytes, tokens) = measure(|| lex(&text));
+    let (borrowed_ast, borrowed_ast_bytes, borrowed_tree) = measure(|| run_ast(tokens));
+
+    let trees: [&Tree; 2] = [&owned_tree, &borrowed_tree];
+    assert_eq!(trees[0].to_string(), trees[1].to_string());
+
+    println!("input: {} bytes", text.len());
+    println!(
+        "{:<10} {:>14} {:>14} {:>14} {:>14}",
+        "", "lex allocs", "lex bytes", "ast allocs", "ast bytes"
+    );
+    println!(
+        "{:<10} {:>14} {:>14} {:>14} {:>14}",
+        "owned", owned_lex, owned_lex_bytes, owned_ast, owned_ast_bytes
+    );
+    println!(
+        "{:<10} {:>14} {:>14} {:>14} {:>14}",
+        "borrowed", borrowed_lex, borrowed_lex_bytes, borrowed_ast, borrowed_ast_bytes
+    );
+
+    let owned: usize = owned_lex + owned_ast;
+    let borrowed: usize = borrowed_lex + borrowed_ast;
+    println!(
+        "borrowed tokens make {:.1}% fewer allocations",
+        100.0 * (owned - borrowed.min(owned)) as f64 / owned as f64
+    );
+    if borrowed >= owned {
+        eprintln!("borrowed tokens should allocate less than owned tokens");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,78 @@
+// Measures lexing, tree building and rendering separately over the checked-in corpora.
+// Run with `cargo bench --bench throughput`, optionally followed by `-- <corpus name>`
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use md_parser::ast::{Tree, run_ast};
+use md_parser::lexer::{Token, lex};
+
+const CORPORA: [&str; 5] = ["readme", "spec", "tables", "code", "nested_lists"];
+
+// Each phase repeats until it has run for at least this long
+const TARGET: Duration = Duration::from_millis(500);
+
+// Repeats <phase> until TARGET is reached; <phase> returns the time of its measured part,
+// leaving out setup and dropping the result
+fn throughput(bytes: usize, mut phase: impl FnMut() -> Duration) -> f64 {
+    let mut elapsed: Duration = Duration::ZERO;
+    let mut iterations: usize = 0;
+    while elapsed < TARGET || iterations < 3 {
+        elapsed += phase();
+        iterations += 1;
+    }
+    (bytes * iterations) as f64 / elapsed.as_secs_f64() / 1_000_000.0
+}
+
+fn main() {
+    // cargo passes "--bench"; anything else narrows down the corpora
+    let filters: Vec<String> = env::args()
+        .skip(1)
+        .filter(|a| !a.starts_with("--"))
+        .collect();
+
+    println!(
+        "{:<14} {:>10} {:>12} {:>12} {:>12}",
+        "corpus", "bytes", "lex MB/s", "ast MB/s", "render MB/s"
+    );
+    for name in CORPORA {
+        if !filters.is_empty() && !filters.iter().any(|f| name.contains(f.as_str())) {
+            continue;
+        }
+        let path: String = format!("{}/benches/corpora/{}.md", env!("CARGO_MANIFEST_DIR"), name);
+        let text: String = fs::read_to_string(&path).expect("corpus is checked in");
+        let bytes: usize = text.len();
+
+        let lex_rate: f64 = throughput(bytes, || {
+            let start: Instant = Instant::now();
+            let tokens: Vec<Token> = black_box(lex(black_box(&text)));
+            let elapsed: Duration = start.elapsed();
+            drop(tokens);
+            elapsed
+        });
+
+        let ast_rate: f64 = throughput(bytes, || {
+            let tokens: Vec<Token> = lex(&text);
+            let start: Instant = Instant::now();
+            let tree: Tree = black_box(run_ast(black_box(tokens)));
+            let elapsed: Duration = start.elapsed();
+            drop(tree);
+            elapsed
+        });
+
+        let tree: Tree = run_ast(lex(&text));
+        let render_rate: f64 = throughput(bytes, || {
+            let start: Instant = Instant::now();
+            let html: String = black_box(black_box(&tree).to_string());
+            let elapsed: Duration = start.elapsed();
+            drop(html);
+            elapsed
+        });
+
+        println!(
+            "{:<14} {:>10} {:>12.1} {:>12.1} {:>12.1}",
+            name, bytes, lex_rate, ast_rate, render_rate
+        );
+    }
+}
@@ -0,0 +1,23 @@
+// String -> html through Document::parse, so front matter and footnotes are included, with
+// every extension on.  Run with `cargo +nightly fuzz run document` from the repository root
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md_parser::ast::Tree;
+use md_parser::document::Document;
+use md_parser::inline::Extensions;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let mut tree: Tree = Document::parse(text).tree();
+        tree.set_extensions(Extensions {
+            strikethrough: true,
+            highlight: true,
+            superscript: true,
+            subscript: false,
+            permalinks: true,
+            md_links: true,
+        });
+        tree.to_string();
+    }
+});
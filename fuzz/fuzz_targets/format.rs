@@ -0,0 +1,11 @@
+// The formatter may refuse an input, but never panic.  Run with `cargo +nightly fuzz run format`
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md_parser::format::format_markdown;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = format_markdown(text, 20);
+    }
+});
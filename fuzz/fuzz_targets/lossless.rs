@@ -0,0 +1,13 @@
+// A lossless tree must give back its source byte for byte.  Run with
+// `cargo +nightly fuzz run lossless`
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md_parser::document::Document;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let source: Option<String> = Document::parse(text).lossless_tree().source();
+        assert_eq!(source.as_deref(), Some(text));
+    }
+});
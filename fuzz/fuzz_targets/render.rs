@@ -0,0 +1,15 @@
+// String -> html through the lexer, tree builder and renderer.
+// Run with `cargo +nightly fuzz run render` from the repository root
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md_parser::ast::run_ast;
+use md_parser::lexer::lex;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let html: String = run_ast(lex(text)).to_string();
+        // Same bound as tests/fuzz_test.rs: no byte of markdown makes more than 128 bytes of html
+        assert!(html.len() <= 64 + 128 * text.len());
+    }
+});
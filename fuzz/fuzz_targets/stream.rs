@@ -0,0 +1,15 @@
+// Bytes -> html through the streaming Lexer, front matter first.  Invalid UTF-8 ends the stream
+// with an error instead of panicking.  Run with `cargo +nightly fuzz run stream`
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use md_parser::ast::run_ast;
+use md_parser::lexer::Lexer;
+
+fuzz_target!(|data: &[u8]| {
+    let mut lexer = Lexer::build(data);
+    if lexer.front_matter().is_ok() {
+        run_ast(&mut lexer).to_string();
+    }
+    let _ = lexer.finish();
+});
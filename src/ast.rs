@@ -1,8 +1,18 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
+use crate::document::Metadata;
+use crate::inline::{
+    Extensions, Footnotes, closing_backticks, escape_html, footnote_references, render_inline,
+    unescape,
+};
 use crate::lexer::Token;
 use crate::lexer::TokenType::*;
+use crate::toc::{TocEntry, is_toc_marker, render_toc};
 use Content::*;
 
 enum TreeState {
@@ -11,57 +21,172 @@ enum TreeState {
     Literal,
 }
 
-pub enum Content {
-    Children(Vec<Rc<RefCell<Node>>>),
-    Inline(String),
+// Text borrows from the markdown source where possible; 'a is the lifetime of that source
+pub enum Content<'a> {
+    Children(Vec<Arc<Mutex<Node<'a>>>>),
+    Inline(Cow<'a, str>),
 }
 
-pub struct Node {
-    parent: Option<Rc<RefCell<Node>>>,
-    tag: String,
-    value: Content,
+pub struct Node<'a> {
+    // Weak, so that a tree never keeps itself alive
+    parent: Option<Weak<Mutex<Node<'a>>>>,
+    tag: Cow<'a, str>,
+    // Rendered inside the opening tag, in insertion order
+    attributes: Vec<(&'static str, Cow<'a, str>)>,
+    // How the markdown was written, where the html does not show it, e.g. the fence of a code
+    // block; kept for writing the markdown back out
+    markup: Vec<(&'static str, Cow<'a, str>)>,
+    value: Content<'a>,
     is_leaf: bool,
+    // Lines of the source the node was built from, counting from 0
+    lines: Range<usize>,
 }
 
-pub struct Tree {
-    root: Rc<RefCell<Node>>,
-    curr: Rc<RefCell<Node>>,
+// A piece of the source of a lossless tree
+enum Segment<'a> {
+    // Bytes that are not part of any text in the tree
+    Trivia(Cow<'a, str>),
+    // A line of a leaf's text, as it was once the tree was built and as it was written; the
+    // two differ where parsing changed it, e.g. `\|` in table cells
+    Text {
+        node: Arc<Mutex<Node<'a>>>,
+        line: usize,
+        parsed: String,
+        written: String,
+    },
 }
 
-impl Node {
-    pub fn build_branch() -> Node {
+pub struct Tree<'a> {
+    root: Arc<Mutex<Node<'a>>>,
+    curr: Arc<Mutex<Node<'a>>>,
+    // Inline syntax used when rendering
+    extensions: Extensions,
+    // Footnote definitions, kept out of the flow.  Once the tree is built, the referenced
+    // ones come first, in reference order; only those are rendered
+    footnotes: Vec<(String, Arc<Mutex<Node<'a>>>)>,
+    referenced_footnotes: usize,
+    // From the front matter; the title and description go in the head of the page
+    metadata: Metadata,
+    // Line of the source being built
+    line: usize,
+    // Every byte of the source, in order, when built with run_ast_lossless
+    source: Option<Vec<Segment<'a>>>,
+    // A line the lexer gave as a whole-line token that became text of the tree, and its
+    // segments; lex_lossless leaves the line in the trivia after the token
+    written: Option<(String, Vec<Segment<'a>>)>,
+    heading_ids: HeadingIds,
+    // Headings of the blocks write_ast has already written, for the table of contents
+    written_headings: Vec<Arc<Mutex<Node<'a>>>>,
+    // Whether write_ast may still take finished blocks out of the tree
+    streaming: bool,
+}
+
+// Nodes are only ever locked by the thread building or printing the tree
+pub fn lock<'n, 'a>(node: &'n Arc<Mutex<Node<'a>>>) -> MutexGuard<'n, Node<'a>> {
+    node.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl<'a> Node<'a> {
+    pub fn build_branch() -> Node<'a> {
         Node {
             parent: None,
-            tag: "".to_string(),
+            tag: Cow::Borrowed(""),
+            attributes: vec![],
+            markup: vec![],
             value: Children(vec![]),
             is_leaf: false,
+            lines: 0..0,
         }
     }
 
-    pub fn build_leaf() -> Node {
+    pub fn build_leaf() -> Node<'a> {
         Node {
             parent: None,
-            tag: "".to_string(),
-            value: Inline("".to_string()),
+            tag: Cow::Borrowed(""),
+            attributes: vec![],
+            markup: vec![],
+            value: Inline(Cow::Borrowed("")),
             is_leaf: true,
+            lines: 0..0,
         }
     }
 
     pub fn get_tag(&self) -> &str {
-        return self.tag.as_str();
+        &self.tag
     }
-    pub fn set_tag(&mut self, new_tag: &mut String) -> () {
-        self.tag = std::mem::take(new_tag);
+
+    // Empty for leaves
+    pub fn children(&self) -> &[Arc<Mutex<Node<'a>>>] {
+        match &self.value {
+            Children(children) => children,
+            Inline(_) => &[],
+        }
     }
 
-    pub fn get_literal(&self) -> Option<String> {
+    // Text of a leaf, as written
+    pub fn literal(&self) -> Option<&str> {
         match &self.value {
-            Inline(text) => Some(text.clone()),
+            Inline(text) => Some(text),
+            Children(_) => None,
+        }
+    }
+
+    pub fn lines(&self) -> Range<usize> {
+        self.lines.clone()
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_ref())
+    }
+
+    // Replaces the attribute if it is already set
+    pub fn set_attribute(&mut self, name: &'static str, value: Cow<'a, str>) {
+        match self.attributes.iter_mut().find(|(key, _)| *key == name) {
+            Some(entry) => entry.1 = value,
+            None => self.attributes.push((name, value)),
+        }
+    }
+
+    pub fn get_markup(&self, name: &str) -> Option<&str> {
+        self.markup
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_ref())
+    }
+
+    // Replaces the markup if it is already set
+    pub fn set_markup(&mut self, name: &'static str, value: Cow<'a, str>) {
+        match self.markup.iter_mut().find(|(key, _)| *key == name) {
+            Some(entry) => entry.1 = value,
+            None => self.markup.push((name, value)),
+        }
+    }
+
+    // The opening tag, with its attributes
+    fn open_tag(&self) -> String {
+        let mut output: String = format!("<{}", self.tag);
+        for (name, value) in &self.attributes {
+            output.push_str(&format!(" {}=\"{}\"", name, value));
+        }
+        output.push('>');
+        output
+    }
+    pub fn set_tag(&mut self, new_tag: &mut Cow<'a, str>) {
+        self.tag = std::mem::take(new_tag);
+    }
+
+    // Moves the text out of a leaf, leaving it empty
+    pub fn take_literal(&mut self) -> Option<Cow<'a, str>> {
+        match &mut self.value {
+            Inline(text) => Some(std::mem::take(text)),
             _ => None,
         }
     }
 
-    pub fn set_value(&mut self, new_content: Content) -> () {
+    pub fn set_value(&mut self, new_content: Content<'a>) {
         match new_content {
             Children(_) => {
                 self.is_leaf = false;
@@ -73,252 +198,1581 @@ impl Node {
         self.value = new_content;
     }
 
-    pub fn append_literal(&mut self, to_add: &mut String) -> () {
-        if let Inline(curr) = &self.value {
-            let mut temp: String = curr.clone();
-            temp.push_str(&to_add);
-            self.set_value(Inline(temp));
+    pub fn append_literal(&mut self, to_add: &str) {
+        if let Inline(curr) = &mut self.value {
+            // Only copies out of the source the first time
+            curr.to_mut().push_str(to_add);
         }
     }
 }
 
-impl Tree {
+impl<'a> Tree<'a> {
     // Builds a tree with a single root node, whose tag is "html"
-    pub fn build() -> Tree {
+    pub fn build() -> Tree<'a> {
         let root_node: Node = Node {
             parent: None,
-            tag: "html".to_string(),
+            tag: Cow::Borrowed("html"),
+            attributes: vec![],
+            markup: vec![],
             value: Children(vec![]),
             is_leaf: false,
+            lines: 0..0,
         };
 
-        let ptr: Rc<RefCell<Node>> = Rc::new(RefCell::new(root_node));
+        let ptr: Arc<Mutex<Node<'a>>> = Arc::new(Mutex::new(root_node));
         Tree {
-            root: Rc::clone(&ptr),
-            curr: Rc::clone(&ptr),
+            root: Arc::clone(&ptr),
+            curr: Arc::clone(&ptr),
+            extensions: Extensions::default(),
+            footnotes: vec![],
+            referenced_footnotes: 0,
+            metadata: Metadata::default(),
+            line: 0,
+            source: None,
+            written: None,
+            heading_ids: HeadingIds::default(),
+            written_headings: vec![],
+            streaming: false,
+        }
+    }
+
+    pub fn root(&self) -> Arc<Mutex<Node<'a>>> {
+        Arc::clone(&self.root)
+    }
+
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    // Moves the line spans of every node down by <lines>, e.g. past front matter that was not
+    // part of the markdown
+    pub fn offset_lines(&mut self, lines: usize) {
+        let footnotes = self.footnotes.iter().map(|(_, footnote)| footnote);
+        for node in std::iter::once(&self.root)
+            .chain(footnotes)
+            .flat_map(descendants)
+        {
+            let mut guard = lock(&node);
+            guard.lines = guard.lines.start + lines..guard.lines.end + lines;
+        }
+    }
+
+    // Headings from <min_level> to <max_level>, each nested under the closest heading of a
+    // lower level before it
+    pub fn toc(&self, min_level: usize, max_level: usize) -> Vec<TocEntry> {
+        let mut output: Vec<TocEntry> = vec![];
+        let written: Vec<Arc<Mutex<Node<'a>>>> = self.written_headings.clone();
+        for node in written.into_iter().chain(headings(&self.root)) {
+            let heading = lock(&node);
+            let level: usize = heading.get_tag()[1..].parse().unwrap_or(1);
+            if !(min_level..=max_level).contains(&level) {
+                continue;
+            }
+            let text: String = match &heading.value {
+                Inline(text) => text.to_string(),
+                Children(_) => String::new(),
+            };
+            let entry: TocEntry = TocEntry {
+                text,
+                level,
+                slug: heading.get_attribute("id").unwrap_or("").to_string(),
+                lines: heading.lines.clone(),
+                children: vec![],
+            };
+
+            // Descend through the last entries while they are of a lower level
+            let mut siblings: &mut Vec<TocEntry> = &mut output;
+            while siblings.last().is_some_and(|last| last.level < level) {
+                siblings = &mut siblings.last_mut().expect("checked above").children;
+            }
+            siblings.push(entry);
         }
+        output
     }
 
     // Inserts a leaf, as a child of curr node
-    pub fn insert_leaf(&mut self, tag: &mut String, literal: &mut String) -> () {
-        let to_add: Rc<RefCell<Node>> = Rc::new(RefCell::new(Node {
-            parent: Some(Rc::clone(&self.curr)),
+    pub fn insert_leaf(&mut self, tag: &mut Cow<'a, str>, literal: &mut Cow<'a, str>) {
+        let to_add: Arc<Mutex<Node<'a>>> = Arc::new(Mutex::new(Node {
+            parent: Some(Arc::downgrade(&self.curr)),
             tag: std::mem::take(tag),
+            attributes: vec![],
+            markup: vec![],
             value: Inline(std::mem::take(literal)),
             is_leaf: true,
+            lines: self.line..self.line + 1,
         }));
-        if let Children(lst) = &mut self.curr.borrow_mut().value {
-            lst.push(Rc::clone(&to_add));
+        if let Children(lst) = &mut lock(&self.curr).value {
+            lst.push(Arc::clone(&to_add));
         }
 
-        self.curr = Rc::clone(&to_add);
+        self.curr = Arc::clone(&to_add);
     }
 
     // Inserts a branch, as a child of curr node
-    pub fn insert_branch(&mut self, tag: &mut String) -> () {
-        let to_add: Rc<RefCell<Node>> = Rc::new(RefCell::new(Node {
-            parent: Some(Rc::clone(&self.curr)),
+    pub fn insert_branch(&mut self, tag: &mut Cow<'a, str>) {
+        let to_add: Arc<Mutex<Node<'a>>> = Arc::new(Mutex::new(Node {
+            parent: Some(Arc::downgrade(&self.curr)),
             tag: std::mem::take(tag),
+            attributes: vec![],
+            markup: vec![],
             value: Children(vec![]),
             is_leaf: false,
+            lines: self.line..self.line + 1,
         }));
-        if let Children(lst) = &mut self.curr.borrow_mut().value {
-            lst.push(Rc::clone(&to_add));
+        if let Children(lst) = &mut lock(&self.curr).value {
+            lst.push(Arc::clone(&to_add));
         }
 
-        self.curr = Rc::clone(&to_add);
+        self.curr = Arc::clone(&to_add);
     }
 
     // Inserts a node, as a child of curr node
-    pub fn insert_node(&mut self, node: Rc<RefCell<Node>>) -> () {
-        node.borrow_mut().parent = Some(Rc::clone(&self.curr));
+    pub fn insert_node(&mut self, node: Arc<Mutex<Node<'a>>>) {
+        lock(&node).parent = Some(Arc::downgrade(&self.curr));
 
-        if let Children(lst) = &mut self.curr.borrow_mut().value {
-            lst.push(Rc::clone(&node));
+        if let Children(lst) = &mut lock(&self.curr).value {
+            lst.push(Arc::clone(&node));
         }
 
-        self.curr = Rc::clone(&node);
+        self.curr = Arc::clone(&node);
     }
 
     // Moves curr pointer up to its parent
-    fn curr_up(&mut self) -> () {
-        let maybe_parent = self.curr.borrow().parent.clone();
+    fn curr_up(&mut self) {
+        let maybe_parent = lock(&self.curr).parent.as_ref().and_then(Weak::upgrade);
         if let Some(parent) = maybe_parent {
             self.curr = parent
         }
     }
 
     // Removes last child of curr; only if curr has children
-    fn remove_curr_youngest(&mut self) -> Option<Rc<RefCell<Node>>> {
-        let mut borrowed = self.curr.borrow_mut();
+    fn remove_curr_youngest(&mut self) -> Option<Arc<Mutex<Node<'a>>>> {
+        let mut borrowed = lock(&self.curr);
         match &mut borrowed.value {
             Children(vec_node) => vec_node.pop(),
             Inline(_) => None,
         }
     }
 
-    pub fn get_curr_tag(&self) -> String {
-        self.curr.borrow().get_tag().to_string()
+    // Last child of curr, if it has any
+    fn curr_youngest(&self) -> Option<Arc<Mutex<Node<'a>>>> {
+        match &lock(&self.curr).value {
+            Children(vec_node) => vec_node.last().cloned(),
+            Inline(_) => None,
+        }
+    }
+
+    // Cheap for tags borrowed from the source or from static strings
+    pub fn get_curr_tag(&self) -> Cow<'a, str> {
+        lock(&self.curr).tag.clone()
+    }
+
+    pub fn set_curr_tag(&mut self, new_tag: &mut Cow<'a, str>) {
+        lock(&self.curr).set_tag(new_tag);
+    }
+
+    pub fn append_curr_literal(&mut self, to_add: &str) {
+        lock(&self.curr).append_literal(to_add);
+    }
+
+    pub fn set_curr_attribute(&mut self, name: &'static str, value: Cow<'a, str>) {
+        lock(&self.curr).set_attribute(name, value);
+    }
+
+    pub fn set_curr_markup(&mut self, name: &'static str, value: Cow<'a, str>) {
+        lock(&self.curr).set_markup(name, value);
+    }
+
+    // The markdown the tree was built from, byte for byte, with any edits to the text of its
+    // leaves.  Lines added to a leaf go after its last line; None unless the tree is lossless
+    pub fn source(&self) -> Option<String> {
+        let segments: &Vec<Segment<'a>> = self.source.as_ref()?;
+        // The last line of each leaf in the source
+        let mut seen: HashSet<*const Mutex<Node<'a>>> = HashSet::new();
+        let mut last: Vec<bool> = vec![false; segments.len()];
+        for (i, segment) in segments.iter().enumerate().rev() {
+            if let Segment::Text { node, .. } = segment {
+                last[i] = seen.insert(Arc::as_ptr(node));
+            }
+        }
+
+        let mut output: String = String::new();
+        let mut cache: LeafLines<'a> = HashMap::new();
+        for (segment, last) in segments.iter().zip(last) {
+            match segment {
+                Segment::Trivia(text) => output.push_str(text),
+                Segment::Text {
+                    node,
+                    line,
+                    parsed,
+                    written,
+                } => {
+                    let lines: &[String] = leaf_lines(&mut cache, node);
+                    let node = lock(node);
+                    // Pipes in edited cells must not split them
+                    let escape = |text: &str| match node.get_tag() {
+                        "th" | "td" => text.replace('|', "\\|"),
+                        _ => text.to_string(),
+                    };
+                    match lines.get(*line) {
+                        Some(text) if text == parsed => output.push_str(written),
+                        Some(text) => output.push_str(&escape(text)),
+                        None => {}
+                    }
+                    if last {
+                        for text in lines.iter().skip(line + 1) {
+                            output.push('\n');
+                            output.push_str(&escape(text));
+                        }
+                    }
+                }
+            }
+        }
+        Some(output)
+    }
+
+    fn is_lossless(&self) -> bool {
+        self.source.is_some()
+    }
+
+    fn push_trivia(&mut self, text: Cow<'a, str>) {
+        let Some(source) = &mut self.source else {
+            return;
+        };
+        if let Some((written, segments)) = self.written.take()
+            && let Some(start) = text.trim_end().strip_suffix(written.as_str()).map(str::len)
+        {
+            let end: usize = start + written.len();
+            source.push(Segment::Trivia(Cow::Owned(text[..start].to_string())));
+            source.extend(segments);
+            source.push(Segment::Trivia(Cow::Owned(text[end..].to_string())));
+            return;
+        }
+        source.push(Segment::Trivia(text));
+    }
+
+    // Records that the last line of <node>'s text so far, line <line> of it, comes next in the
+    // source, as it is now
+    fn push_text(&mut self, node: &Arc<Mutex<Node<'a>>>, line: usize) {
+        if let Some(source) = &mut self.source {
+            source.push(Tree::text_segment(node, line));
+        }
+    }
+
+    fn text_segment(node: &Arc<Mutex<Node<'a>>>, line: usize) -> Segment<'a> {
+        let written: String = {
+            let guard = lock(node);
+            let text: &str = guard.literal().unwrap_or("");
+            text[text.rfind('\n').map_or(0, |end| end + 1)..].to_string()
+        };
+        Segment::Text {
+            node: Arc::clone(node),
+            line,
+            parsed: written.clone(),
+            written,
+        }
+    }
+
+    // Like push_text, for the text of the whole-line token just read
+    fn push_written_text(&mut self, written: &str, node: &Arc<Mutex<Node<'a>>>, line: usize) {
+        if self.is_lossless() {
+            self.written = Some((written.to_string(), vec![Tree::text_segment(node, line)]));
+        }
+    }
+
+    // Takes back the latest line recorded for <node>, returning where it was and how it was
+    // written
+    fn take_text(&mut self, node: &Arc<Mutex<Node<'a>>>) -> Option<(usize, String)> {
+        let source: &mut Vec<Segment<'a>> = self.source.as_mut()?;
+        let index: usize = source.iter().rposition(|segment| {
+            matches!(segment, Segment::Text { node: other, .. } if Arc::ptr_eq(other, node))
+        })?;
+        match source.remove(index) {
+            Segment::Text { written, .. } => Some((index, written)),
+            Segment::Trivia(_) => None,
+        }
+    }
+
+    // Segments of a table row: its cells, and the pipes and spaces around them as trivia.  Cells
+    // past the header's are not in the table, so they are trivia too
+    fn row_segments(row: &str, cells: &[Arc<Mutex<Node<'a>>>]) -> Vec<Segment<'a>> {
+        let mut output: Vec<Segment<'a>> = vec![];
+        let mut consumed: usize = 0;
+        for (i, (start, end)) in cell_ranges(row).into_iter().enumerate() {
+            let Some(cell) = cells.get(i) else {
+                break;
+            };
+            let text: &str = row[start..end].trim();
+            let start: usize = start + (row[start..end].len() - row[start..end].trim_start().len());
+            output.push(Segment::Trivia(Cow::Owned(
+                row[consumed..start].to_string(),
+            )));
+            output.push(Segment::Text {
+                node: Arc::clone(cell),
+                line: 0,
+                parsed: String::new(),
+                written: text.to_string(),
+            });
+            consumed = start + text.len();
+        }
+        output.push(Segment::Trivia(Cow::Owned(row[consumed..].to_string())));
+        output
+    }
+
+    fn push_row(&mut self, row: &str, cells: &[Arc<Mutex<Node<'a>>>]) {
+        if let Some(source) = &mut self.source {
+            source.extend(Tree::row_segments(row, cells));
+        }
+    }
+
+    // Like push_row, for a row read as a whole-line token
+    fn push_written_row(&mut self, row: &str, cells: &[Arc<Mutex<Node<'a>>>]) {
+        if self.is_lossless() {
+            self.written = Some((row.to_string(), Tree::row_segments(row, cells)));
+        }
+    }
+
+    // Notes how each recorded line reads once the tree is built, so that edits can be told apart
+    fn snapshot_source(&mut self) {
+        let mut cache: LeafLines<'a> = HashMap::new();
+        for segment in self.source.iter_mut().flatten() {
+            if let Segment::Text {
+                node, line, parsed, ..
+            } = segment
+            {
+                *parsed = leaf_lines(&mut cache, node)
+                    .get(*line)
+                    .cloned()
+                    .unwrap_or_default();
+            }
+        }
+    }
+
+    // Puts the definitions that are referenced first, in the order of their first reference.
+    // Definitions can reference each other, so they are searched once they are numbered
+    fn number_footnotes(&mut self) {
+        // The first definition of a label is the one used
+        let mut definitions: HashMap<String, usize> = HashMap::new();
+        for (i, (label, _)) in self.footnotes.iter().enumerate() {
+            definitions.entry(label.clone()).or_insert(i);
+        }
+
+        // Indices of the definitions in the order they are numbered
+        let mut numbered: Vec<usize> = vec![];
+        let mut is_numbered: HashSet<usize> = HashSet::new();
+        let mut pending: Vec<String> = footnote_labels(&self.root);
+        let mut searched: usize = 0;
+        loop {
+            for label in pending.drain(..) {
+                if let Some(&index) = definitions.get(&label)
+                    && is_numbered.insert(index)
+                {
+                    numbered.push(index);
+                }
+            }
+            let Some(&index) = numbered.get(searched) else {
+                break;
+            };
+            pending = footnote_labels(&self.footnotes[index].1);
+            searched += 1;
+        }
+
+        let mut definitions: Vec<Option<(String, Arc<Mutex<Node<'a>>>)>> =
+            std::mem::take(&mut self.footnotes)
+                .into_iter()
+                .map(Some)
+                .collect();
+        self.referenced_footnotes = numbered.len();
+        for index in numbered {
+            self.footnotes.extend(definitions[index].take());
+        }
+        // Unused and repeated definitions are kept for writing the markdown back out
+        self.footnotes.extend(definitions.into_iter().flatten());
     }
 
-    pub fn set_curr_tag(&mut self, new_tag: &mut String) -> () {
-        self.curr.borrow_mut().set_tag(new_tag);
+    // Every footnote definition, referenced ones first
+    pub fn footnote_definitions(&self) -> Vec<Arc<Mutex<Node<'a>>>> {
+        self.footnotes
+            .iter()
+            .map(|(_, node)| Arc::clone(node))
+            .collect()
+    }
+
+    // The html of the whole tree, without a newline at the end.  <open> is false when the
+    // opening tag and head were already written, as write_ast does
+    fn write_html(&self, builder: &mut dyn Write, open: bool) -> fmt::Result {
+        let mut state: RenderState = RenderState {
+            footnotes: Footnotes::build(
+                self.footnotes[..self.referenced_footnotes]
+                    .iter()
+                    .map(|(label, _)| label.clone())
+                    .collect(),
+            ),
+            // Taken before the root is locked for rendering
+            toc: Some(self.toc(1, 6)),
+        };
+        if open {
+            self.write_open(builder)?;
+        }
+        let blocks: Vec<Arc<Mutex<Node<'a>>>> = lock(&self.root).children().to_vec();
+        for block in &blocks {
+            self.display_helper(builder, block, 1, 4, &mut state)?;
+        }
+        // The footnotes go at the end of the document
+        if self.referenced_footnotes > 0 {
+            self.footnotes_helper(builder, 1, 4, &mut state)?;
+        }
+        write!(builder, "</{}>", lock(&self.root).get_tag())
     }
 
-    pub fn append_curr_literal(&mut self, to_add: &mut String) -> () {
-        self.curr.borrow_mut().append_literal(to_add);
+    // Opening tag of the root, then the head
+    fn write_open(&self, builder: &mut dyn Write) -> fmt::Result {
+        writeln!(builder, "{}", lock(&self.root).open_tag())?;
+        self.head_helper(builder, 1, 4)
     }
 
-    // Helper for the display trait.  This generates the string to print with the tab formatting
+    // Helper for the display trait.  This writes the html of <node> with the tab formatting.
+    // Nodes wait on a stack rather than in recursive calls, as containers can nest deeply
     fn display_helper(
         &self,
-        builder: &mut String,
-        target: &Node,
+        builder: &mut dyn Write,
+        node: &Arc<Mutex<Node<'a>>>,
         depth: usize,
         tab_size: usize,
-    ) -> () {
-        builder.push_str(&" ".repeat(depth * tab_size));
-        match &target.value {
-            Children(vec_node) => {
-                // Multiple children
-                builder.push_str(&format!("<{}>\n", target.tag));
-                for node in vec_node {
-                    self.display_helper(builder, &node.borrow(), depth + 1, tab_size);
+        state: &mut RenderState,
+    ) -> fmt::Result {
+        let mut pending: Vec<Pending<'a>> = vec![Pending::Open(Arc::clone(node), depth)];
+        while let Some(next) = pending.pop() {
+            let (node, depth): (Arc<Mutex<Node<'a>>>, usize) = match next {
+                Pending::Open(node, depth) => (node, depth),
+                Pending::Close(tag, depth) => {
+                    writeln!(builder, "{}</{}>", indent(depth, tab_size), tag)?;
+                    continue;
                 }
-                builder.push_str(&" ".repeat(depth * tab_size));
-                builder.push_str(&format!("</{}>\n", target.tag));
+            };
+            let target = lock(&node);
+            // The first marker is replaced by the outline of the whole document
+            if let Inline(text) = &target.value
+                && matches!(target.get_tag(), "p" | "")
+                && is_toc_marker(text)
+                && let Some(toc) = state.toc.take()
+            {
+                builder.write_str(&render_toc(&toc, self.extensions, depth, tab_size))?;
+                continue;
+            }
+            builder.write_str(&indent(depth, tab_size))?;
+            match &target.value {
+                Children(vec_node) => {
+                    // Multiple children, then the closing tag
+                    writeln!(builder, "{}", target.open_tag())?;
+                    pending.push(Pending::Close(target.tag.clone(), depth));
+                    for child in vec_node.iter().rev() {
+                        pending.push(Pending::Open(Arc::clone(child), depth + 1));
+                    }
+                }
+                Inline(_) if target.tag == "hr" => builder.write_str("<hr>\n")?,
+                Inline(text) if target.tag.is_empty() => {
+                    // Bare text, e.g. the paragraphs of a tight list
+                    writeln!(
+                        builder,
+                        "{}",
+                        render_inline(text, self.extensions, &mut state.footnotes)
+                    )?;
+                }
+                Inline(text) => {
+                    // Code is shown as written
+                    let mut html: String = if target.tag == "code" {
+                        escape_html(text)
+                    } else {
+                        render_inline(text, self.extensions, &mut state.footnotes)
+                    };
+                    if self.extensions.permalinks
+                        && is_heading(&target.tag)
+                        && let Some(id) = target.get_attribute("id")
+                    {
+                        html.insert_str(
+                            0,
+                            &format!(
+                                "<a class=\"anchor\" href=\"#{}\" aria-hidden=\"true\">#</a>",
+                                id
+                            ),
+                        );
+                    }
+                    writeln!(
+                        builder,
+                        "{}{}</{}>",
+                        target.open_tag(),
+                        html,
+                        target.get_tag()
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Gives every heading an id, in document order, after those of the blocks already written
+    fn set_heading_ids(&mut self) {
+        let mut ids: HeadingIds = std::mem::take(&mut self.heading_ids);
+        for node in headings(&self.root) {
+            ids.assign(&mut lock(&node));
+        }
+        self.heading_ids = ids;
+    }
+
+    // Takes the top-level blocks that later lines can no longer change, all but the last, and
+    // readies them for writing.  A block with a footnote reference or a table of contents
+    // marker needs the rest of the document first, so it and everything after it stay
+    fn take_finished(&mut self, containers: &mut Containers<'a>) -> Vec<Arc<Mutex<Node<'a>>>> {
+        if !self.streaming {
+            return vec![];
+        }
+        // Lists only the loose list keeps alive belong to blocks already written
+        containers.loose.retain(|list| Arc::strong_count(list) > 1);
+
+        let mut root = lock(&self.root);
+        let Children(blocks) = &mut root.value else {
+            return vec![];
+        };
+        let mut finished: usize = 0;
+        while finished + 1 < blocks.len() {
+            let needs_rest: bool = !footnote_labels(&blocks[finished]).is_empty()
+                || descendants(&blocks[finished])
+                    .iter()
+                    .any(|node| matches!(&lock(node).value, Inline(text) if is_toc_marker(text)));
+            if needs_rest {
+                self.streaming = false;
+                break;
             }
-            Inline(text) => {
-                builder.push_str(&format!(
-                    "<{}>{}</{}>\n",
-                    target.get_tag(),
-                    text,
-                    target.get_tag()
-                ));
+            finished += 1;
+        }
+        let output: Vec<Arc<Mutex<Node<'a>>>> = blocks.drain(..finished).collect();
+        drop(root);
+
+        for block in &output {
+            containers.tighten(block);
+            for heading in headings(block) {
+                self.heading_ids.assign(&mut lock(&heading));
+                self.written_headings.push(heading);
+            }
+        }
+        output
+    }
+
+    // Title and description of the page, when the front matter gives them, and the style of
+    // the permalinks
+    fn head_helper(&self, builder: &mut dyn Write, depth: usize, tab_size: usize) -> fmt::Result {
+        let title: Option<&str> = self.metadata.title();
+        let description: Option<&str> = self.metadata.description();
+        if title.is_none() && description.is_none() && !self.extensions.permalinks {
+            return Ok(());
+        }
+        let indent = |depth: usize| indent(depth, tab_size);
+        writeln!(builder, "{}<head>", indent(depth))?;
+        if let Some(title) = title {
+            writeln!(
+                builder,
+                "{}<title>{}</title>",
+                indent(depth + 1),
+                escape_html(title)
+            )?;
+        }
+        if let Some(description) = description {
+            writeln!(
+                builder,
+                "{}<meta name=\"description\" content=\"{}\">",
+                indent(depth + 1),
+                escape_html(description).replace('"', "&quot;")
+            )?;
+        }
+        if self.extensions.permalinks {
+            writeln!(builder, "{}{}", indent(depth + 1), PERMALINK_STYLE)?;
+        }
+        writeln!(builder, "{}</head>", indent(depth))
+    }
+
+    // Numbered list of the footnotes, each ending with a link back to its first reference
+    fn footnotes_helper(
+        &self,
+        builder: &mut dyn Write,
+        depth: usize,
+        tab_size: usize,
+        state: &mut RenderState,
+    ) -> fmt::Result {
+        let indent = |depth: usize| indent(depth, tab_size);
+        writeln!(builder, "{}<section class=\"footnotes\">", indent(depth))?;
+        writeln!(builder, "{}<ol>", indent(depth + 1))?;
+        for (i, (_, node)) in self.footnotes[..self.referenced_footnotes]
+            .iter()
+            .enumerate()
+        {
+            writeln!(builder, "{}<li id=\"fn-{}\">", indent(depth + 2), i + 1)?;
+            if let Children(blocks) = &lock(node).value {
+                for block in blocks {
+                    self.display_helper(builder, block, depth + 3, tab_size, state)?;
+                }
             }
+            writeln!(
+                builder,
+                "{}<a href=\"#fnref-{}\" class=\"footnote-backref\">\u{21a9}</a>",
+                indent(depth + 3),
+                i + 1
+            )?;
+            writeln!(builder, "{}</li>", indent(depth + 2))?;
         }
+        writeln!(builder, "{}</ol>", indent(depth + 1))?;
+        writeln!(builder, "{}</section>", indent(depth))
     }
 }
 
-impl std::fmt::Display for Tree {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut output: String = String::new();
-        self.display_helper(&mut output, &self.root.borrow(), 0, 4);
-        write!(f, "{}", output.trim())
+// Levels of nesting past which html is not indented any further, so that the size of the
+// output does not grow with the square of the nesting
+const MAX_INDENT: usize = 8;
+
+// Spaces before a line of html at <depth>
+pub(crate) fn indent(depth: usize, tab_size: usize) -> String {
+    " ".repeat(depth.min(MAX_INDENT) * tab_size)
+}
+
+// Permalinks only show while the pointer is over their heading
+const PERMALINK_STYLE: &str = "<style>.anchor { visibility: hidden; } \
+    :is(h1, h2, h3, h4, h5, h6):hover .anchor { visibility: visible; }</style>";
+
+pub fn is_heading(tag: &str) -> bool {
+    matches!(tag, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+}
+
+// <node> and every node under it, in document order.  Walked with a stack rather than by
+// recursion, so that deep nesting cannot overflow the call stack
+fn descendants<'a>(node: &Arc<Mutex<Node<'a>>>) -> Vec<Arc<Mutex<Node<'a>>>> {
+    let mut output: Vec<Arc<Mutex<Node<'a>>>> = vec![];
+    let mut pending: Vec<Arc<Mutex<Node<'a>>>> = vec![Arc::clone(node)];
+    while let Some(node) = pending.pop() {
+        pending.extend(lock(&node).children().iter().rev().cloned());
+        output.push(node);
     }
+    output
+}
+
+// Heading leaves under <node>, in document order
+fn headings<'a>(node: &Arc<Mutex<Node<'a>>>) -> Vec<Arc<Mutex<Node<'a>>>> {
+    descendants(node)
+        .into_iter()
+        .filter(|node| {
+            let guard = lock(node);
+            matches!(guard.value, Inline(_)) && is_heading(guard.get_tag())
+        })
+        .collect()
 }
 
-pub fn run_ast(token_vec: Vec<Token>) -> Tree {
+// Ids given to headings so far
+#[derive(Default)]
+struct HeadingIds {
+    used: HashSet<String>,
+    // How many times each id has been asked for again
+    repeats: HashMap<String, usize>,
+}
+
+impl HeadingIds {
+    // Gives <heading> its `{#custom-id}`, or else a slug of its text.  An id already used gets
+    // "-1", "-2", ... as on GitHub; text with nothing to make a slug from gets no id
+    fn assign(&mut self, heading: &mut Node<'_>) {
+        let Some(mut text) = heading.take_literal() else {
+            return;
+        };
+        let id: String = match custom_id(&text) {
+            Some((before, id)) => {
+                let id: String = id.to_string();
+                heading.set_markup("id", Cow::Owned(id.clone()));
+                text = match text {
+                    Cow::Borrowed(source) => Cow::Borrowed(&source[..before]),
+                    Cow::Owned(mut owned) => {
+                        owned.truncate(before);
+                        Cow::Owned(owned)
+                    }
+                };
+                id
+            }
+            None => slug(&text),
+        };
+        heading.set_value(Inline(text));
+        if !id.is_empty() {
+            heading.set_attribute("id", Cow::Owned(self.unique(id)));
+        }
+    }
+
+    fn unique(&mut self, id: String) -> String {
+        if self.used.insert(id.clone()) {
+            return id;
+        }
+        let repeats: &mut usize = self.repeats.entry(id.clone()).or_insert(0);
+        loop {
+            *repeats += 1;
+            let candidate: String = format!("{}-{}", id, repeats);
+            if self.used.insert(candidate.clone()) {
+                return candidate;
+            }
+        }
+    }
+}
+
+// GitHub's anchor for a heading: the text it renders to, lowercase, with punctuation dropped and
+// whitespace as hyphens
+pub fn slug(text: &str) -> String {
+    let html: String = render_inline(text, Extensions::default(), &mut Footnotes::default());
+    plain_text(&html)
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            '-' | '_' => Some(c),
+            _ if c.is_whitespace() => Some('-'),
+            _ if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+// The text of <html>, without its tags and with the characters escape_html escapes put back
+fn plain_text(html: &str) -> String {
+    let mut output: String = String::with_capacity(html.len());
+    let mut rest: &str = html;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find('>')
+            .map_or("", |end| &rest[start + end + 1..]);
+    }
+    output.push_str(rest);
+    output
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+// The id of a heading ending in `{#custom-id}`, and where the text before it ends
+fn custom_id(text: &str) -> Option<(usize, &str)> {
+    let inner: &str = text.trim_end().strip_suffix('}')?;
+    let start: usize = inner.rfind("{#")?;
+    let id: &str = &inner[start + 2..];
+    if id.is_empty() || id.contains(|c: char| c.is_whitespace() || "\"'<>&{}".contains(c)) {
+        return None;
+    }
+    Some((text[..start].trim_end().len(), id))
+}
+
+// Labels of the footnote references under <node>, in document order
+fn footnote_labels(node: &Arc<Mutex<Node<'_>>>) -> Vec<String> {
+    descendants(node)
+        .iter()
+        .flat_map(|node| {
+            let guard = lock(node);
+            match &guard.value {
+                Inline(text) if guard.get_tag() != "code" => footnote_references(text),
+                _ => vec![],
+            }
+        })
+        .collect()
+}
+
+// A node display_helper has yet to render, or the closing tag of a branch it has opened
+enum Pending<'a> {
+    Open(Arc<Mutex<Node<'a>>>, usize),
+    Close(Cow<'a, str>, usize),
+}
+
+// What rendering needs to know beyond the node being rendered
+struct RenderState {
+    footnotes: Footnotes,
+    // Shown in place of the first table of contents marker; later ones stay as text, so that
+    // the outline is not repeated
+    toc: Option<Vec<TocEntry>>,
+}
+
+impl fmt::Display for Tree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_html(f, true)
+    }
+}
+
+// Splits a table row into trimmed cells, borrowing them from the source where possible.
+// Pipes that are escaped or inside code spans do not separate cells, and `\|` becomes `|`
+fn split_row(row: Cow<'_, str>) -> Vec<Cow<'_, str>> {
+    let cells: Vec<(usize, usize)> = cell_ranges(&row);
+    let unescape =
+        |cell: &str| -> Option<String> { cell.contains("\\|").then(|| cell.replace("\\|", "|")) };
+    match row {
+        Cow::Borrowed(text) => cells
+            .into_iter()
+            .map(|(start, end)| {
+                let cell: &str = text[start..end].trim();
+                unescape(cell).map_or(Cow::Borrowed(cell), Cow::Owned)
+            })
+            .collect(),
+        Cow::Owned(text) => cells
+            .into_iter()
+            .map(|(start, end)| {
+                let cell: &str = text[start..end].trim();
+                Cow::Owned(unescape(cell).unwrap_or_else(|| cell.to_string()))
+            })
+            .collect(),
+    }
+}
+
+// Byte ranges of the cells of a row; a leading and a trailing pipe are optional
+fn cell_ranges(row: &str) -> Vec<(usize, usize)> {
+    let bytes: &[u8] = row.as_bytes();
+    let end: usize = row.trim_end().len();
+    let mut i: usize = row.len() - row.trim_start().len();
+    let mut after_pipe: bool = false;
+    if bytes.get(i) == Some(&b'|') {
+        i += 1;
+        after_pipe = true;
+    }
+
+    let mut output: Vec<(usize, usize)> = Vec::new();
+    let mut start: usize = i;
+    while i < end {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => {
+                let run: usize = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+                i = closing_backticks(bytes, i + run, run).unwrap_or(i) + run;
+            }
+            b'|' => {
+                output.push((start, i));
+                i += 1;
+                start = i;
+                after_pipe = true;
+                continue;
+            }
+            _ => i += 1,
+        }
+        after_pipe = false;
+    }
+    if !(after_pipe && start >= end) {
+        output.push((start, end.max(start)));
+    }
+    output
+}
+
+// A container prefix pushed by the lexer at the start of a line
+enum Marker {
+    Quote,
+    // List tag, indent of the item's content, number of the first item, and the bullet or the
+    // delimiter after the number
+    Item(&'static str, usize, usize, String),
+    // Label of a footnote definition
+    Footnote(String),
+    Indent(usize),
+}
+
+fn parse_marker(value: &str) -> Option<Marker> {
+    let mut words = value.split(' ');
+    let kind: &str = words.next()?;
+    let mut number = || words.next().and_then(|word| word.parse::<usize>().ok());
+    match kind {
+        "blockquote" => Some(Marker::Quote),
+        "indent" => Some(Marker::Indent(number()?)),
+        "ul" => {
+            let width: usize = number()?;
+            let bullet: &str = value.split(' ').nth(2).unwrap_or("-");
+            Some(Marker::Item("ul", width, 1, bullet.to_string()))
+        }
+        "ol" => {
+            let width: usize = number()?;
+            let start: usize = number().unwrap_or(1);
+            let delimiter: &str = value.split(' ').nth(3).unwrap_or(".");
+            Some(Marker::Item("ol", width, start, delimiter.to_string()))
+        }
+        "footnote" => Some(Marker::Footnote(value["footnote ".len()..].to_string())),
+        _ => None,
+    }
+}
+
+// Most blockquotes, list items and footnote definitions a line can be nested in
+pub const MAX_NESTING: usize = 32;
+
+// Blockquotes, list items and footnote definitions that stay open from one line to the next
+struct Containers<'a> {
+    // Outermost first; list items and footnotes with the indent their content needs,
+    // blockquotes with None
+    open: Vec<(Arc<Mutex<Node<'a>>>, Option<usize>)>,
+    // Lists that were open at the latest blank line
+    blank_lists: Vec<Arc<Mutex<Node<'a>>>>,
+    // Lists whose items are separated by blank lines; their paragraphs keep the p tag
+    loose: Vec<Arc<Mutex<Node<'a>>>>,
+}
+
+impl<'a> Containers<'a> {
+    fn build() -> Containers<'a> {
+        Containers {
+            open: vec![],
+            blank_lists: vec![],
+            loose: vec![],
+        }
+    }
+
+    // Lists holding the open list items, outermost first
+    fn open_lists(&self) -> Vec<Arc<Mutex<Node<'a>>>> {
+        self.open
+            .iter()
+            .filter(|(item, _)| lock(item).get_tag() == "li")
+            .filter_map(|(item, _)| lock(item).parent.as_ref().and_then(Weak::upgrade))
+            .collect()
+    }
+
+    // Matches the markers of a new line against the open containers, closing the ones it does
+    // not continue and opening the new ones.  Returns the indentation used up by list items
+    fn reconcile(&mut self, output: &mut Tree<'a>, markers: Vec<Marker>, blank: bool) -> usize {
+        let mut markers = markers.into_iter().peekable();
+        let mut indent: usize = 0;
+        let mut consumed: usize = 0;
+        let mut matched: usize = 0;
+
+        while matched < self.open.len() {
+            while let Some(Marker::Indent(columns)) = markers.peek() {
+                indent += columns;
+                markers.next();
+            }
+            match self.open[matched].1 {
+                None if matches!(markers.peek(), Some(Marker::Quote)) => {
+                    markers.next();
+                    indent = 0;
+                    consumed = 0;
+                }
+                Some(width) if indent >= width => {
+                    indent -= width;
+                    consumed += width;
+                }
+                _ => break,
+            }
+            matched += 1;
+        }
+
+        // Blank lines do not end list items, only blockquotes
+        if blank {
+            while matched < self.open.len() && self.open[matched].1.is_some() {
+                matched += 1;
+            }
+        }
+
+        let markers: Vec<Marker> = markers.collect();
+        let opens_new: bool = markers
+            .iter()
+            .any(|marker| !matches!(marker, Marker::Indent(_)));
+        if matched < self.open.len() || opens_new {
+            self.open.truncate(matched);
+            output.curr = match self.open.last() {
+                Some((node, _)) => Arc::clone(node),
+                None => Arc::clone(&output.root),
+            };
+        }
+
+        for marker in markers {
+            // Markers past the deepest container allowed are dropped, and the line goes in it
+            if self.open.len() >= MAX_NESTING && !matches!(marker, Marker::Indent(_)) {
+                continue;
+            }
+            match marker {
+                Marker::Quote => {
+                    output.insert_branch(&mut "blockquote".into());
+                    self.open.push((Arc::clone(&output.curr), None));
+                    indent = 0;
+                }
+                Marker::Item(tag, width, start, marker) => {
+                    // Items with the same marker continue the list just before them
+                    let continues = |list: &Node| {
+                        list.get_tag() == tag && list.get_markup("marker") == Some(&marker)
+                    };
+                    match output.curr_youngest() {
+                        Some(list) if continues(&lock(&list)) => output.curr = list,
+                        _ => {
+                            output.insert_branch(&mut tag.into());
+                            output.set_curr_markup("marker", marker.into());
+                            if start != 1 {
+                                output.set_curr_attribute("start", start.to_string().into());
+                            }
+                        }
+                    }
+                    output.insert_branch(&mut "li".into());
+                    self.open
+                        .push((Arc::clone(&output.curr), Some(indent + width)));
+                    indent = 0;
+                }
+                Marker::Footnote(label) => {
+                    // Not part of the flow; rendered after everything else
+                    let mut node: Node<'a> = Node::build_branch();
+                    node.parent = Some(Arc::downgrade(&output.curr));
+                    node.tag = "footnote".into();
+                    // Labels are matched without regard to case, but kept as written
+                    let key: String = label.to_lowercase();
+                    node.set_markup("label", label.into());
+                    let node: Arc<Mutex<Node<'a>>> = Arc::new(Mutex::new(node));
+                    // The first definition of a label is the one used
+                    output.footnotes.push((key, Arc::clone(&node)));
+                    output.curr = Arc::clone(&node);
+                    // Later paragraphs of the footnote are indented by 4
+                    self.open.push((node, Some(indent + 4)));
+                    indent = 0;
+                }
+                Marker::Indent(columns) => indent += columns,
+            }
+        }
+
+        if blank {
+            self.blank_lists = self.open_lists();
+        } else if !self.blank_lists.is_empty() {
+            // A blank line between blocks of the innermost list still open
+            let open_lists: Vec<Arc<Mutex<Node<'a>>>> = self.open_lists();
+            if let Some(list) = self
+                .blank_lists
+                .iter()
+                .rev()
+                .find(|list| open_lists.iter().any(|open| Arc::ptr_eq(open, list)))
+            {
+                self.loose.push(Arc::clone(list));
+            }
+            self.blank_lists.clear();
+        }
+        consumed
+    }
+
+    // Drops the p tag of paragraphs directly inside the items of tight lists
+    fn tighten(&self, node: &Arc<Mutex<Node<'a>>>) {
+        for list in descendants(node) {
+            let guard = lock(&list);
+            if !matches!(guard.get_tag(), "ul" | "ol")
+                || self.loose.iter().any(|loose| Arc::ptr_eq(loose, &list))
+            {
+                continue;
+            }
+            for item in guard.children() {
+                for block in lock(item).children() {
+                    let mut block = lock(block);
+                    if block.is_leaf && block.get_tag() == "p" {
+                        block.set_tag(&mut "".into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Lines of the text of leaves, split once however many segments refer to them
+type LeafLines<'a> = HashMap<*const Mutex<Node<'a>>, Vec<String>>;
+
+fn leaf_lines<'c, 'a>(cache: &'c mut LeafLines<'a>, node: &Arc<Mutex<Node<'a>>>) -> &'c [String] {
+    cache.entry(Arc::as_ptr(node)).or_insert_with(|| {
+        text_lines(lock(node).literal().unwrap_or(""))
+            .into_iter()
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+// Lines of a leaf's text; code ends with a line ending, which does not start another line
+fn text_lines(text: &str) -> Vec<&str> {
+    text.strip_suffix('\n')
+        .unwrap_or(text)
+        .split('\n')
+        .collect()
+}
+
+// Takes the last line off a paragraph of more than one line
+fn split_last_line<'a>(paragraph: &mut Node<'a>) -> Option<Cow<'a, str>> {
+    let text: Cow<'a, str> = paragraph.take_literal()?;
+    let Some(split) = text.rfind('\n') else {
+        paragraph.set_value(Inline(text));
+        return None;
+    };
+    let (rest, last): (Cow<'a, str>, Cow<'a, str>) = match text {
+        Cow::Borrowed(text) => (
+            Cow::Borrowed(&text[..split]),
+            Cow::Borrowed(&text[split + 1..]),
+        ),
+        Cow::Owned(text) => (
+            Cow::Owned(text[..split].to_string()),
+            Cow::Owned(text[split + 1..].to_string()),
+        ),
+    };
+    paragraph.set_value(Inline(rest));
+    paragraph.lines.end -= 1;
+    Some(last)
+}
+
+// The kind of a whole-line token and the line as written, which the lexer gives after the kind
+// of lines that are text where they cannot be markup, e.g. "table left |:--|"
+fn split_written(value: &str) -> (&str, Option<&str>) {
+    if !matches!(value.split(' ').next(), Some("table" | "h1" | "h2")) {
+        return (value, None);
+    }
+    match value.find(['|', ':', '-', '=']) {
+        Some(start) => (value[..start].trim_end(), Some(&value[start..])),
+        None => (value, None),
+    }
+}
+
+// Whether the last line of <paragraph> has as many cells as the delimiter row <row>, which it
+// needs to be the header of a table
+fn header_fits(paragraph: &Arc<Mutex<Node<'_>>>, row: &str) -> bool {
+    let guard = lock(paragraph);
+    let header: &str = guard
+        .literal()
+        .unwrap_or("")
+        .rsplit('\n')
+        .next()
+        .unwrap_or("");
+    split_row(Cow::Borrowed(header)).len() == split_row(Cow::Borrowed(row)).len()
+}
+
+// Adds <text> as the next line of the paragraph that ends curr, or else as a new paragraph.
+// Returns the paragraph and which of its lines <text> is
+fn paragraph_line<'a>(
+    output: &mut Tree<'a>,
+    mut text: Cow<'a, str>,
+    in_paragraph: bool,
+    literal_lines: &mut usize,
+) -> (Arc<Mutex<Node<'a>>>, usize) {
+    match output.curr_youngest().filter(|_| in_paragraph) {
+        Some(paragraph) => {
+            {
+                let mut paragraph = lock(&paragraph);
+                paragraph.append_literal("\n");
+                paragraph.append_literal(&text);
+                paragraph.lines.end = output.line + 1;
+            }
+            *literal_lines += 1;
+            (paragraph, *literal_lines - 1)
+        }
+        None => {
+            output.insert_leaf(&mut "p".into(), &mut text);
+            let paragraph: Arc<Mutex<Node<'a>>> = Arc::clone(&output.curr);
+            output.curr_up();
+            *literal_lines = 1;
+            (paragraph, 0)
+        }
+    }
+}
+
+// Adds a body row to the table at curr, with as many cells as its header while <padding>
+// lasts.  Returns the cells
+fn table_row<'a>(
+    output: &mut Tree<'a>,
+    row: Cow<'a, str>,
+    table_align: &[Cow<'a, str>],
+    columns: usize,
+    padding: &mut usize,
+) -> Vec<Arc<Mutex<Node<'a>>>> {
+    // Body rows go in a tbody, which is only made once there is a row
+    if output.get_curr_tag() == "table" {
+        output.insert_branch(&mut "tbody".into());
+    }
+    *padding += row.len();
+    let mut cells: Vec<Cow<'a, str>> = split_row(row);
+    let missing: usize = columns.saturating_sub(cells.len());
+    if missing <= *padding {
+        *padding -= missing;
+        cells.resize(columns, Cow::Borrowed(""));
+    }
+
+    output.insert_branch(&mut "tr".into());
+    let mut nodes: Vec<Arc<Mutex<Node<'a>>>> = vec![];
+    for (i, mut col) in cells.into_iter().enumerate() {
+        output.insert_leaf(&mut "td".into(), &mut col);
+        set_align(output, table_align, i);
+        nodes.push(Arc::clone(&output.curr));
+        output.curr_up();
+    }
+    output.curr_up();
+    nodes
+}
+
+// Sets the align attribute of the cell at curr, if its column has one
+fn set_align<'a>(output: &mut Tree<'a>, table_align: &[Cow<'a, str>], column: usize) {
+    if let Some(align) = table_align.get(column).filter(|align| *align != "none") {
+        output.set_curr_attribute("align", align.clone());
+    }
+}
+
+// Builds the tree from any stream of tokens, e.g. a Vec or a lexer::Lexer
+pub fn run_ast<'a>(tokens: impl IntoIterator<Item = Token<'a>>) -> Tree<'a> {
+    build_tree(tokens, Tree::build(), None)
+}
+
+// Builds a tree that keeps every byte of the source, from the tokens of lexer::lex_lossless, so
+// that Tree::source can write it back out
+pub fn run_ast_lossless<'a>(tokens: impl IntoIterator<Item = Token<'a>>) -> Tree<'a> {
     let mut output: Tree = Tree::build();
-    if token_vec.is_empty() {
-        return output;
+    output.source = Some(vec![]);
+    build_tree(tokens, output, None)
+}
+
+// Builds the tree of <tokens> as run_ast does, writing the same html as its Display to <writer>
+// along the way: each top-level block once the next one starts, so that the html of a long
+// document is not all held at once, and the rest at the end
+pub fn write_ast<'a>(
+    tokens: impl IntoIterator<Item = Token<'a>>,
+    extensions: Extensions,
+    metadata: Metadata,
+    writer: &mut dyn io::Write,
+) -> io::Result<()> {
+    let mut output: Tree = Tree::build();
+    output.set_extensions(extensions);
+    output.set_metadata(metadata);
+    output.streaming = true;
+
+    let mut html: IoWriter = IoWriter {
+        inner: writer,
+        error: None,
+    };
+    // Blocks written before the end have no footnote references and no table of contents
+    let mut state: RenderState = RenderState {
+        footnotes: Footnotes::build(vec![]),
+        toc: None,
+    };
+    let mut result: fmt::Result = output.write_open(&mut html);
+    let output: Tree = build_tree(
+        tokens,
+        output,
+        Some(&mut |tree: &Tree<'a>, block: &Arc<Mutex<Node<'a>>>| {
+            if result.is_ok() {
+                result = tree.display_helper(&mut html, block, 1, 4, &mut state);
+            }
+        }),
+    );
+    if result.is_ok() {
+        result = output.write_html(&mut html, false);
+    }
+    match (result, html.error) {
+        (Ok(()), _) => Ok(()),
+        (Err(_), Some(error)) => Err(error),
+        (Err(_), None) => Err(io::Error::other("Cannot render the html")),
     }
+}
+
+// Lets html be written to an io::Write, keeping the error that stopped it
+struct IoWriter<'w> {
+    inner: &'w mut dyn io::Write,
+    error: Option<io::Error>,
+}
 
+impl Write for IoWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.inner.write_all(text.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            fmt::Error
+        })
+    }
+}
+
+// Called by build_tree with each top-level block it takes out of a streaming tree
+type WriteBlock<'w, 'a> = &'w mut dyn FnMut(&Tree<'a>, &Arc<Mutex<Node<'a>>>);
+
+fn build_tree<'a>(
+    tokens: impl IntoIterator<Item = Token<'a>>,
+    mut output: Tree<'a>,
+    mut write: Option<WriteBlock<'_, 'a>>,
+) -> Tree<'a> {
     let mut tree_state: TreeState = TreeState::Start;
-    let mut open_tag: String;
-    let mut open_text: String;
+    let mut open_tag: Cow<'a, str>;
+    let mut open_text: Cow<'a, str>;
+    // Column alignments of the table being built, from its delimiter row
+    let mut table_align: Vec<Cow<'a, str>> = vec![];
+    let mut table_columns: usize = 0;
+    // Empty cells that short rows of the table may still be padded with: one per byte of the
+    // table so far, so that the html stays in proportion to the source
+    let mut table_padding: usize = 0;
+
+    let mut containers: Containers = Containers::build();
+    // Container prefixes of the line being read, until its content starts
+    let mut markers: Vec<Marker> = vec![];
+    let mut at_line_start: bool = true;
+    // Indentation belonging to list items, stripped from code lines
+    let mut line_indent: usize = 0;
+    // Whether the last child of curr is a paragraph that the next line of text continues
+    let mut in_paragraph: bool = false;
+    // Lines of text in the paragraph or code block being built, so that its text need not be
+    // counted again for each line
+    let mut literal_lines: usize = 0;
+    let mut lines_read: usize = 0;
+
+    for mut token in tokens {
+        if let Trivia = token.token_type {
+            output.push_trivia(token.value);
+            continue;
+        }
+        if let (Prefix, Some(marker)) = (&token.token_type, parse_marker(&token.value)) {
+            markers.push(marker);
+            continue;
+        }
+        if at_line_start {
+            if let Some(write) = write.as_mut() {
+                for block in output.take_finished(&mut containers) {
+                    write(&output, &block);
+                }
+            }
+            output.line = lines_read;
+            lines_read += 1;
+            let blank: bool = match token.token_type {
+                Suffix => token.value == "empty_line",
+                Literal => token.value.trim().is_empty(),
+                Prefix | Trivia => false,
+            };
+            let curr: Arc<Mutex<Node<'a>>> = Arc::clone(&output.curr);
+            line_indent = containers.reconcile(&mut output, std::mem::take(&mut markers), blank);
+            // A paragraph does not carry on into another container
+            if !Arc::ptr_eq(&curr, &output.curr) {
+                in_paragraph = false;
+            }
+
+            // Only rows continue a table, delimiter rows and underlines that are not breaks included
+            let curr_tag: Cow<'a, str> = output.get_curr_tag();
+            let row: bool = match token.token_type {
+                Literal => true,
+                Suffix => {
+                    split_written(&token.value).1.is_some() && !token.value.starts_with("h2 hr")
+                }
+                Prefix | Trivia => false,
+            };
+            if matches!(curr_tag.as_ref(), "table" | "tbody") && !row {
+                if curr_tag == "tbody" {
+                    output.curr_up();
+                }
+                output.curr_up();
+            }
+        }
+        // Headings are a prefix and a literal on the same line
+        at_line_start = !matches!(token.token_type, Prefix);
 
-    for mut token in token_vec {
         match token.token_type {
             Prefix => {
                 // Create branch node with given tag
                 open_tag = std::mem::take(&mut token.value);
-                output.insert_leaf(&mut open_tag, &mut "".to_string());
+                output.insert_leaf(&mut open_tag, &mut "".into());
                 output.curr_up();
 
                 tree_state = TreeState::Prefix;
+                in_paragraph = false;
             }
             Suffix => {
-                // Assumes <curr> points to the node to edit
-                match token.value.as_str() {
-                    "empty_line" => match output.get_curr_tag().as_str() {
-                        "table" => {
-                            output.curr_up();
-                        }
-                        _ => {}
-                    },
-                    "code_block" => {
+                // Assumes <curr> points to the node to edit.  Whether the line is paragraph text
+                let (kind, written) = split_written(&token.value);
+                let in_table: bool = matches!(output.get_curr_tag().as_ref(), "table" | "tbody");
+                in_paragraph = match kind {
+                    code if code.split(' ').next() == Some("code_block") => {
                         if output.get_curr_tag() == "code" {
                             // exit block
                             output.curr_up();
                             output.curr_up();
                         } else {
-                            // start block
-                            output.insert_branch(&mut "pre".to_string());
-                            output.insert_leaf(&mut "code".to_string(), &mut "".to_string());
+                            // start block; then the fence and info string
+                            let mut words = code.splitn(3, ' ').skip(1);
+                            let fence: String = words.next().unwrap_or("```").to_string();
+                            let info: Option<String> = words.next().map(str::to_string);
+
+                            output.insert_branch(&mut "pre".into());
+                            literal_lines = 0;
+                            output.set_curr_markup("fence", fence.into());
+                            output.insert_leaf(&mut "code".into(), &mut "".into());
+                            if let Some(info) = info {
+                                // The info string is kept as written for the formatter; the
+                                // class gets its escapes and entities decoded
+                                let language: String =
+                                    unescape(info.split(' ').next().unwrap_or(""));
+                                output.set_curr_attribute(
+                                    "class",
+                                    format!(
+                                        "language-{}",
+                                        escape_html(&language).replace('"', "&quot;")
+                                    )
+                                    .into(),
+                                );
+                                output.set_curr_markup("info", info.into());
+                            }
                         }
+                        false
+                    }
+                    // Inside a table any line but a break is just another row
+                    row if in_table
+                        && row != "h2 hr"
+                        && let Some(row) = written =>
+                    {
+                        let cells: Vec<Arc<Mutex<Node<'a>>>> = table_row(
+                            &mut output,
+                            Cow::Owned(row.to_string()),
+                            &table_align,
+                            table_columns,
+                            &mut table_padding,
+                        );
+                        output.push_written_row(row, &cells);
+                        false
                     }
-                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
-                        open_tag = std::mem::take(&mut token.value);
-                        let target: Rc<RefCell<Node>> = output.remove_curr_youngest().expect("");
-                        target.borrow_mut().set_tag(&mut open_tag);
+                    "h1" | "h2" | "h2 hr"
+                        if in_paragraph && let Some(target) = output.remove_curr_youngest() =>
+                    {
+                        // The whole paragraph above becomes the heading
+                        open_tag = match kind {
+                            "h1" => "h1".into(),
+                            _ => "h2".into(),
+                        };
+                        lock(&target).set_tag(&mut open_tag);
+                        // Through the underline
+                        lock(&target).lines.end = output.line + 1;
                         output.insert_node(target);
                         output.curr_up();
+                        false
                     }
-                    "table" => {
-                        // Change parent node
+                    "hr" | "h2 hr" => {
+                        output.insert_leaf(&mut "hr".into(), &mut "".into());
                         output.curr_up();
-                        let prev: Rc<RefCell<Node>> = output.remove_curr_youngest().expect("");
-                        let token_headers: Option<String> = prev.borrow().get_literal();
-
-                        open_tag = std::mem::take(&mut token.value);
-                        output.insert_branch(&mut open_tag);
-                        output.insert_branch(&mut "tr".to_string());
+                        false
+                    }
+                    // Tables only start under a paragraph, whose last line is the header
+                    table
+                        if table.split(' ').next() == Some("table")
+                            && in_paragraph
+                            && let Some(paragraph) = output.curr_youngest()
+                            && written.is_none_or(|row| header_fits(&paragraph, row)) =>
+                    {
+                        // Remaining words are the column alignments
+                        table_align = table
+                            .split(' ')
+                            .skip(1)
+                            .map(|align| Cow::Owned(align.to_string()))
+                            .collect();
 
-                        if let Some(text) = token_headers {
-                            for col in text.split('|').filter(|s| !s.is_empty()) {
-                                output.insert_leaf(&mut "th".to_string(), &mut col.to_string());
-                                output.curr_up();
+                        let last_line: Option<Cow<'a, str>> =
+                            split_last_line(&mut lock(&paragraph));
+                        let header: Cow<'a, str> = match last_line {
+                            Some(header) => header,
+                            // The paragraph is only the header
+                            None => {
+                                output.remove_curr_youngest();
+                                lock(&paragraph).take_literal().unwrap_or_default()
                             }
+                        };
+                        // Its line was recorded as text of the paragraph
+                        let recorded: Option<(usize, String)> = output.take_text(&paragraph);
+
+                        table_padding = header.len() + written.map_or(0, str::len);
+                        output.insert_branch(&mut "table".into());
+                        output.insert_branch(&mut "thead".into());
+                        output.insert_branch(&mut "tr".into());
+
+                        let headers: Vec<Cow<'a, str>> = split_row(header);
+                        table_columns = headers.len();
+                        let mut cells: Vec<Arc<Mutex<Node<'a>>>> = vec![];
+                        for (i, mut col) in headers.into_iter().enumerate() {
+                            output.insert_leaf(&mut "th".into(), &mut col);
+                            set_align(&mut output, &table_align, i);
+                            cells.push(Arc::clone(&output.curr));
+                            output.curr_up();
                         }
                         output.curr_up();
+                        output.curr_up();
+                        if let (Some((index, row)), Some(source)) = (recorded, &mut output.source) {
+                            let segments: Vec<Segment<'a>> = Tree::row_segments(&row, &cells);
+                            source.splice(index..index, segments);
+                        }
+                        false
                     }
-                    _ => {}
-                }
+                    // Anywhere else delimiter rows and underlines are text
+                    _ if let Some(text) = written => {
+                        let (paragraph, line) = paragraph_line(
+                            &mut output,
+                            Cow::Owned(text.to_string()),
+                            in_paragraph,
+                            &mut literal_lines,
+                        );
+                        output.push_written_text(text, &paragraph, line);
+                        true
+                    }
+                    _ => false,
+                };
 
                 tree_state = TreeState::Start;
             }
             Literal => {
-                match output.get_curr_tag().as_str() {
-                    "table" => {
+                match output.get_curr_tag().as_ref() {
+                    "table" | "tbody" => {
                         open_text = std::mem::take(&mut token.value);
-                        output.insert_branch(&mut "tr".to_string());
-                        for col in open_text.split('|').filter(|s| !s.is_empty()) {
-                            output.insert_leaf(&mut "td".to_string(), &mut col.to_string());
-                            output.curr_up();
+                        let row: Option<String> =
+                            output.is_lossless().then(|| open_text.to_string());
+                        let cells: Vec<Arc<Mutex<Node<'a>>>> = table_row(
+                            &mut output,
+                            open_text,
+                            &table_align,
+                            table_columns,
+                            &mut table_padding,
+                        );
+                        if let Some(row) = row {
+                            output.push_row(&row, &cells);
                         }
-                        output.curr_up();
                     }
                     "code" => {
                         open_text = std::mem::take(&mut token.value);
-                        output.append_curr_literal(&mut open_text);
+                        let spaces: usize =
+                            open_text.len() - open_text.trim_start_matches(' ').len();
+                        let stripped: usize = spaces.min(line_indent);
+                        if output.is_lossless() {
+                            output.push_trivia(Cow::Owned(open_text[..stripped].to_string()));
+                        }
+                        output.append_curr_literal(&open_text[stripped..]);
+                        let curr: Arc<Mutex<Node<'a>>> = Arc::clone(&output.curr);
+                        output.push_text(&curr, literal_lines);
+                        literal_lines += 1;
+                        output.append_curr_literal("\n");
                     }
                     _ => match tree_state {
-                        TreeState::Start => {
+                        TreeState::Start | TreeState::Literal => {
                             open_text = std::mem::take(&mut token.value);
-                            output.insert_leaf(&mut "p".to_string(), &mut open_text);
-                            output.curr_up();
+                            let (paragraph, line) = paragraph_line(
+                                &mut output,
+                                open_text,
+                                in_paragraph,
+                                &mut literal_lines,
+                            );
+                            output.push_text(&paragraph, line);
+                            in_paragraph = true;
                         }
                         TreeState::Prefix => {
                             open_text = std::mem::take(&mut token.value);
-                            let target: Rc<RefCell<Node>> =
-                                output.remove_curr_youngest().expect("");
-                            target.borrow_mut().set_value(Inline(open_text));
-                            output.insert_node(target);
-                            output.curr_up();
-                        }
-                        TreeState::Literal => {
-                            open_text = std::mem::take(&mut token.value);
-                            output.insert_leaf(&mut "p".to_string(), &mut open_text);
+                            match output.remove_curr_youngest() {
+                                Some(target) => {
+                                    lock(&target).set_value(Inline(open_text));
+                                    output.insert_node(target);
+                                }
+                                None => output.insert_leaf(&mut "p".into(), &mut open_text),
+                            }
+                            let heading: Arc<Mutex<Node<'a>>> = Arc::clone(&output.curr);
+                            output.push_text(&heading, 0);
                             output.curr_up();
+                            in_paragraph = false;
                         }
                     },
                 }
 
                 tree_state = TreeState::Literal;
             }
+            Trivia => unreachable!("trivia is recorded as soon as it is read"),
         }
     }
 
+    containers.tighten(&output.root);
+    for (_, footnote) in &output.footnotes {
+        containers.tighten(footnote);
+    }
+    output.number_footnotes();
+    output.set_heading_ids();
+    output.snapshot_source();
     output
 }
@@ -0,0 +1,215 @@
+use std::any::Any;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::Config;
+use crate::ast::Tree;
+use crate::document::Document;
+use crate::inline::Extensions;
+
+pub struct Summary {
+    pub converted: Vec<PathBuf>,
+    pub copied: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+// A single unit of work: a markdown file to convert, or an asset to copy.  A directory that
+// could not be walked is kept with the reason, and fails when run
+pub(crate) enum Job {
+    Convert(PathBuf, PathBuf),
+    Copy(PathBuf, PathBuf),
+    Unreadable(PathBuf, String),
+}
+
+impl Job {
+    pub(crate) fn source(&self) -> &Path {
+        match self {
+            Job::Convert(src, _) | Job::Copy(src, _) | Job::Unreadable(src, _) => src,
+        }
+    }
+
+    pub(crate) fn run(&self, extensions: Extensions) -> Result<(), Box<dyn Error>> {
+        match self {
+            Job::Convert(src, dest) => convert_file(src, dest, extensions),
+            Job::Copy(src, dest) => copy_file(src, dest),
+            Job::Unreadable(_, reason) => Err(reason.as_str().into()),
+        }
+    }
+}
+
+// Converts every markdown file under config.md_path into config.output_path,
+// copying all other files alongside.  Work is spread over config.jobs threads
+pub fn run_batch(config: &Config) -> Result<Summary, Box<dyn Error>> {
+    let jobs: Vec<Job> = plan_jobs(config)?;
+    let results: Vec<Result<(), String>> = run_jobs(&jobs, config.jobs, config.extensions);
+
+    // Summarise in walk order, whichever thread finished first
+    let mut summary: Summary = Summary {
+        converted: Vec::new(),
+        copied: Vec::new(),
+        failed: Vec::new(),
+    };
+    for (job, result) in jobs.into_iter().zip(results) {
+        let (src, done) = match job {
+            Job::Convert(src, _) => (src, Some(&mut summary.converted)),
+            Job::Copy(src, _) => (src, Some(&mut summary.copied)),
+            Job::Unreadable(src, _) => (src, None),
+        };
+        match (result, done) {
+            (Ok(()), Some(done)) => done.push(src),
+            (Err(reason), _) => summary.failed.push((src, reason)),
+            (Ok(()), None) => unreachable!("an unreadable directory always fails"),
+        }
+    }
+
+    Ok(summary)
+}
+
+// Lists the work for converting config.md_path, in walk order
+pub(crate) fn plan_jobs(config: &Config) -> Result<Vec<Job>, Box<dyn Error>> {
+    let in_dir: &Path = Path::new(&config.md_path);
+    let out_dir: &Path = Path::new(&config.output_path);
+
+    let mut jobs: Vec<Job> = Vec::new();
+    // The output directory is never walked, in case it is nested inside the input
+    let mut seen: HashSet<PathBuf> = [in_dir, out_dir]
+        .into_iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok())
+        .collect();
+    collect_jobs(in_dir, in_dir, out_dir, &mut seen, &mut jobs)?;
+    Ok(jobs)
+}
+
+// Runs every job on a pool of scoped threads; results line up with <jobs>
+fn run_jobs(jobs: &[Job], threads: usize, extensions: Extensions) -> Vec<Result<(), String>> {
+    let next: AtomicUsize = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<(), String>>>> =
+        Mutex::new((0..jobs.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let index: usize = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(index) else {
+                        break;
+                    };
+                    // A panic fails this file only, and leaves the results unpoisoned
+                    let result: Result<(), String> =
+                        panic::catch_unwind(AssertUnwindSafe(|| job.run(extensions)))
+                            .map_err(|payload| panic_message(payload.as_ref()))
+                            .and_then(|result| result.map_err(|e| e.to_string()));
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every job is claimed by a thread"))
+        .collect()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message: &str = match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload.downcast_ref::<String>().map_or("", String::as_str),
+    };
+    format!("panicked: {message}")
+}
+
+// Walks <dir> recursively in sorted order, mirroring each path relative to <root> under <out_dir>.
+// <seen> holds the canonical paths of directories already walked, so that symlinks back up the
+// tree do not loop
+fn collect_jobs(
+    dir: &Path,
+    root: &Path,
+    out_dir: &Path,
+    seen: &mut HashSet<PathBuf>,
+    jobs: &mut Vec<Job>,
+) -> Result<(), Box<dyn Error>> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        let hidden: bool = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+
+        let dest: PathBuf = out_dir.join(path.strip_prefix(root)?);
+        if path.is_dir() {
+            if !seen.insert(fs::canonicalize(&path)?) {
+                continue;
+            }
+            // The rest of the tree is still converted
+            if let Err(error) = collect_jobs(&path, root, out_dir, seen, jobs) {
+                jobs.push(Job::Unreadable(path, error.to_string()));
+            }
+        } else if is_markdown(&path) {
+            jobs.push(Job::Convert(path, dest.with_extension("html")));
+        } else if path != dest {
+            jobs.push(Job::Copy(path, dest));
+        }
+    }
+    Ok(())
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "md")
+}
+
+fn convert_file(src: &Path, dest: &Path, extensions: Extensions) -> Result<(), Box<dyn Error>> {
+    let contents: String = fs::read_to_string(src)?;
+    let mut ast: Tree = Document::parse(&contents).tree();
+    // Links between the pages point at the converted ones
+    ast.set_extensions(Extensions {
+        md_links: true,
+        ..extensions
+    });
+
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file: File = File::create(dest)?;
+    write!(file, "{}", ast)?;
+    Ok(())
+}
+
+fn copy_file(src: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(src, dest)?;
+    Ok(())
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, reason) in &self.failed {
+            writeln!(f, "FAILED {}: {}", path.display(), reason)?;
+        }
+        write!(
+            f,
+            "Converted {} file(s), copied {} asset(s), {} failed",
+            self.converted.len(),
+            self.copied.len(),
+            self.failed.len()
+        )
+    }
+}
@@ -1,33 +1,280 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+
+use crate::inline::Extensions;
+
+// Path that stands for stdin (as input) or stdout (as output)
+pub const STDIO_PATH: &str = "-";
+
+// Default port of the preview server
+pub const DEFAULT_PORT: u16 = 8000;
+
+// Default column paragraphs are wrapped at by fmt
+pub const DEFAULT_WIDTH: usize = 80;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    // Convert markdown to html files
+    Convert,
+    // Render markdown on request over http
+    Serve,
+    // Print the outline of a markdown file as a markdown list
+    Toc,
+    // Rewrite markdown files in the canonical style
+    Fmt,
+}
+
 pub struct Config {
+    pub command: Command,
     pub md_path: String,
     pub output_path: String,
+    pub out_dir: Option<String>,
+    // Worker threads used in batch mode
+    pub jobs: usize,
+    // Keep running, reconverting whenever a source changes
+    pub watch: bool,
+    // Port of the preview server
+    pub port: u16,
+    // Inline syntax beyond CommonMark
+    pub extensions: Extensions,
+    // fmt only lists the files it would change
+    pub check: bool,
+    // Column fmt wraps paragraphs at; 0 never wraps
+    pub width: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            command: Command::Convert,
+            md_path: STDIO_PATH.to_string(),
+            output_path: STDIO_PATH.to_string(),
+            out_dir: None,
+            jobs: 1,
+            watch: false,
+            port: DEFAULT_PORT,
+            extensions: Extensions::default(),
+            check: false,
+            width: DEFAULT_WIDTH,
+        }
+    }
 }
 
 impl Config {
     pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
         args.next(); // skip path of executable
+        let mut args = args.peekable();
+
+        let command: Command = match args.peek().map(String::as_str) {
+            Some("serve") => Command::Serve,
+            Some("toc") => Command::Toc,
+            Some("fmt") => Command::Fmt,
+            _ => Command::Convert,
+        };
+        if command != Command::Convert {
+            args.next();
+        }
 
         // Read program arguments
-        let md_path: String = match args.next() {
+        let mut positional: Vec<String> = Vec::new();
+        let mut output_flag: Option<String> = None;
+        let mut out_dir: Option<String> = None;
+        let mut watch: bool = false;
+        let mut port: u16 = DEFAULT_PORT;
+        let mut extensions: Extensions = Extensions::default();
+        let mut check: bool = false;
+        let mut width: usize = DEFAULT_WIDTH;
+        let mut jobs: usize = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => match args.next() {
+                    Some(path) => output_flag = Some(path),
+                    None => return Err("Missing path after -o"),
+                },
+                "--out-dir" => match args.next() {
+                    Some(path) => out_dir = Some(path),
+                    None => return Err("Missing path after --out-dir"),
+                },
+                "-w" | "--watch" => watch = true,
+                "--strikethrough" => extensions.strikethrough = true,
+                "--highlight" => extensions.highlight = true,
+                "--superscript" => extensions.superscript = true,
+                "--subscript" => extensions.subscript = true,
+                "--permalinks" => extensions.permalinks = true,
+                "-p" | "--port" => match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => port = n,
+                    None => return Err("Expected a port number after -p"),
+                },
+                "--check" => check = true,
+                "--width" => match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => width = n,
+                    None => return Err("Expected a number of columns after --width"),
+                },
+                "-j" | "--jobs" => match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => jobs = n,
+                    _ => return Err("Expected a positive number after -j"),
+                },
+                _ if arg.starts_with('-') && arg != STDIO_PATH => {
+                    return Err("Unknown option");
+                }
+                _ => positional.push(arg),
+            }
+        }
+        if positional.len() > 2 || (positional.len() == 2 && output_flag.is_some()) {
+            return Err("Too many arguments");
+        }
+        if out_dir.is_some() && (positional.len() == 2 || output_flag.is_some()) {
+            return Err("Cannot combine --out-dir with an output file");
+        }
+        let mut positional = positional.into_iter();
+
+        if command == Command::Serve {
+            // The server renders in memory; it only needs the directory to serve
+            let md_path: String = positional.next().unwrap_or(".".to_string());
+            if positional.next().is_some() || output_flag.is_some() || out_dir.is_some() {
+                return Err("serve takes a single directory");
+            }
+            return Ok(Config {
+                command,
+                output_path: md_path.clone(),
+                md_path,
+                jobs,
+                port,
+                extensions,
+                ..Config::default()
+            });
+        }
+
+        let md_path: String = match positional.next() {
             Some(path) => path,
+            // Piped input with no file given
+            None if !io::stdin().is_terminal() => STDIO_PATH.to_string(),
             None => return Err("No markdown file specified"),
         };
 
-        let output_path: String = match args.next() {
-            Some(path) => path,
-            None => {
-                let mut fallback_name: String = match md_path.split('.').nth(0) {
-                    Some(text) => text.to_string(),
-                    None => "output".to_string(),
-                };
-                fallback_name.push_str(".html");
-                fallback_name
+        if command == Command::Toc {
+            // The outline is written to stdout unless told otherwise
+            if watch || out_dir.is_some() || Path::new(&md_path).is_dir() {
+                return Err("toc takes a single markdown file");
+            }
+            return Ok(Config {
+                command,
+                md_path,
+                output_path: output_flag
+                    .or(positional.next())
+                    .unwrap_or(STDIO_PATH.to_string()),
+                extensions,
+                ..Config::default()
+            });
+        }
+        if command == Command::Fmt {
+            // Files are rewritten in place; stdin is formatted to stdout
+            if watch || out_dir.is_some() || output_flag.is_some() || positional.next().is_some() {
+                return Err("fmt takes a single markdown file or directory");
             }
+            return Ok(Config {
+                command,
+                output_path: md_path.clone(),
+                md_path,
+                check,
+                width,
+                ..Config::default()
+            });
+        }
+        if watch && md_path == STDIO_PATH {
+            return Err("--watch needs a markdown file or directory");
+        }
+
+        let output_path: String = match output_flag.or(positional.next()) {
+            Some(path) => path,
+            None if md_path == STDIO_PATH => match out_dir {
+                Some(_) => return Err("--out-dir needs a markdown file"),
+                None => STDIO_PATH.to_string(),
+            },
+            // Batch conversion writes next to the sources unless told otherwise
+            None if Path::new(&md_path).is_dir() => match &out_dir {
+                Some(dir) => Path::new(dir)
+                    .join(relative_to_current_dir(Path::new(&md_path))?)
+                    .to_string_lossy()
+                    .into_owned(),
+                None => md_path.clone(),
+            },
+            None => derive_output_path(Path::new(&md_path), out_dir.as_deref().map(Path::new))?
+                .to_string_lossy()
+                .into_owned(),
         };
 
         Ok(Config {
+            command,
             md_path,
             output_path,
+            out_dir,
+            jobs,
+            watch,
+            port,
+            extensions,
+            ..Config::default()
         })
     }
+
+    pub fn reads_stdin(&self) -> bool {
+        self.md_path == STDIO_PATH
+    }
+
+    pub fn writes_stdout(&self) -> bool {
+        self.output_path == STDIO_PATH
+    }
+
+    // Whether a whole directory tree is being converted
+    pub fn is_batch(&self) -> bool {
+        Path::new(&self.md_path).is_dir()
+    }
+}
+
+// Swaps the final extension of <md_path> for ".html", keeping its directory.
+// With <out_dir>, the input's path relative to the current directory is mirrored under that
+// directory instead, as it is for each file of a directory input; inputs outside the current
+// directory are rejected
+pub fn derive_output_path(md_path: &Path, out_dir: Option<&Path>) -> Result<PathBuf, &'static str> {
+    let mut output: PathBuf = match out_dir {
+        Some(dir) => dir.join(relative_to_current_dir(md_path)?),
+        None => md_path.to_path_buf(),
+    };
+
+    if output.file_name().is_none() {
+        output.push("output");
+    }
+    output.set_extension("html");
+    Ok(output)
+}
+
+// Resolves "." and ".." in <path> without touching the filesystem, failing if it leaves the
+// current directory
+fn relative_to_current_dir(path: &Path) -> Result<PathBuf, &'static str> {
+    const OUTSIDE: &str = "--out-dir needs an input inside the current directory";
+    let current_dir: PathBuf;
+    let path: &Path = if path.is_absolute() {
+        current_dir = env::current_dir().map_err(|_| "Cannot read the current directory")?;
+        path.strip_prefix(&current_dir).map_err(|_| OUTSIDE)?
+    } else {
+        path
+    };
+
+    let mut relative: PathBuf = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(OUTSIDE);
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(OUTSIDE),
+        }
+    }
+    Ok(relative)
 }
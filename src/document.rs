@@ -0,0 +1,231 @@
+// A markdown file split into its front matter and the markdown after it.  Front matter is a
+// YAML block fenced by "---" or a TOML block fenced by "+++", at the very start of the file
+
+use std::borrow::Cow;
+use std::io;
+
+use crate::ast::{Tree, run_ast, run_ast_lossless, write_ast};
+use crate::inline::Extensions;
+use crate::lexer::{Token, TokenType, lex_iter, lex_lossless};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    // The format opened by a fence line, if it is one
+    pub fn from_fence(line: &str) -> Option<Format> {
+        match line.trim_end() {
+            "---" => Some(Format::Yaml),
+            "+++" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    // YAML documents may also end with "..."
+    pub fn is_closing_fence(&self, line: &str) -> bool {
+        matches!(
+            (self, line.trim_end()),
+            (Format::Yaml, "---" | "...") | (Format::Toml, "+++")
+        )
+    }
+}
+
+// Longest front matter, in bytes between the fences.  A file that opens with a fence but does
+// not close it soon after is markdown, so that the streaming lexer never buffers more than this
+pub const MAX_FRONT_MATTER: usize = 64 * 1024;
+
+pub struct FrontMatter<'a> {
+    pub format: Format,
+    // Lines between the fences, as written
+    pub raw: Cow<'a, str>,
+}
+
+impl<'a> FrontMatter<'a> {
+    // Splits the front matter off the start of <contents>, returning it and the rest.
+    // Without a closing fence within MAX_FRONT_MATTER there is no front matter
+    pub fn split(contents: &'a str) -> Option<(FrontMatter<'a>, &'a str)> {
+        let mut lines = contents.split_inclusive('\n');
+        let format: Format = Format::from_fence(lines.next()?)?;
+        let start: usize = contents.find('\n')? + 1;
+
+        let mut end: usize = start;
+        for line in lines {
+            if end - start > MAX_FRONT_MATTER {
+                return None;
+            }
+            if format.is_closing_fence(line) {
+                let front_matter: FrontMatter = FrontMatter {
+                    format,
+                    raw: Cow::Borrowed(&contents[start..end]),
+                };
+                return Some((front_matter, &contents[end + line.len()..]));
+            }
+            end += line.len();
+        }
+        None
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::parse(self.format, &self.raw)
+    }
+
+    // Lines taken up in the source, fences included
+    pub fn line_count(&self) -> usize {
+        self.raw.lines().count() + 2
+    }
+
+    // Detaches the front matter from its source text
+    pub fn into_owned(self) -> FrontMatter<'static> {
+        FrontMatter {
+            format: self.format,
+            raw: Cow::Owned(self.raw.into_owned()),
+        }
+    }
+}
+
+// Top level `key: value` (YAML) or `key = value` (TOML) pairs of front matter.  Nested
+// values, lists and TOML tables are skipped; this is not a full YAML or TOML parser
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Metadata {
+    entries: Vec<(String, String)>,
+}
+
+impl Metadata {
+    pub fn parse(format: Format, raw: &str) -> Metadata {
+        let separator: char = match format {
+            Format::Yaml => ':',
+            Format::Toml => '=',
+        };
+        let mut entries: Vec<(String, String)> = vec![];
+        for line in raw.lines() {
+            // Indented lines belong to a nested value
+            if line.starts_with([' ', '\t']) || line.starts_with('#') {
+                continue;
+            }
+            // Keys after a TOML table header belong to the table
+            if format == Format::Toml && line.starts_with('[') {
+                break;
+            }
+            let Some((key, value)) = line.split_once(separator) else {
+                continue;
+            };
+            let key: &str = unquote(key.trim());
+            let value: &str = value.trim();
+            if key.is_empty() || value.is_empty() {
+                continue;
+            }
+            entries.push((key.to_string(), unquote(value).to_string()));
+        }
+        Metadata { entries }
+    }
+
+    // The first value given for <key>
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // All pairs, in the order they are written
+    pub fn entries(&self) -> &[(String, String)] {
+        &self.entries
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.get("title")
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.get("description")
+    }
+}
+
+// Strips one pair of matching quotes
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2
+            && let Some(inner) = value
+                .strip_prefix(quote)
+                .and_then(|rest| rest.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+pub struct Document<'a> {
+    // The whole file, front matter included
+    source: &'a str,
+    front_matter: Option<FrontMatter<'a>>,
+    // Markdown after the front matter
+    body: &'a str,
+}
+
+impl<'a> Document<'a> {
+    pub fn parse(contents: &'a str) -> Document<'a> {
+        match FrontMatter::split(contents) {
+            Some((front_matter, body)) => Document {
+                source: contents,
+                front_matter: Some(front_matter),
+                body,
+            },
+            None => Document {
+                source: contents,
+                front_matter: None,
+                body: contents,
+            },
+        }
+    }
+
+    pub fn front_matter(&self) -> Option<&FrontMatter<'a>> {
+        self.front_matter.as_ref()
+    }
+
+    pub fn body(&self) -> &'a str {
+        self.body
+    }
+
+    // Empty when there is no front matter
+    pub fn metadata(&self) -> Metadata {
+        self.front_matter
+            .as_ref()
+            .map(FrontMatter::metadata)
+            .unwrap_or_default()
+    }
+
+    // Tree of the body, with the metadata that goes in the head of the page
+    pub fn tree(&self) -> Tree<'a> {
+        self.with_metadata(run_ast(lex_iter(self.body)))
+    }
+
+    // Html of the body, as Tree's Display gives it, written to <writer> while the tree is built
+    pub fn write_html(&self, extensions: Extensions, writer: &mut dyn io::Write) -> io::Result<()> {
+        write_ast(lex_iter(self.body), extensions, self.metadata(), writer)
+    }
+
+    // Like tree, but keeping every byte of the file for Tree::source.  The front matter is kept
+    // as written, as trivia
+    pub fn lossless_tree(&self) -> Tree<'a> {
+        let front_matter: &'a str = &self.source[..self.source.len() - self.body.len()];
+        let front_matter = (!front_matter.is_empty()).then_some(Token {
+            token_type: TokenType::Trivia,
+            value: Cow::Borrowed(front_matter),
+        });
+        self.with_metadata(run_ast_lossless(
+            front_matter.into_iter().chain(lex_lossless(self.body)),
+        ))
+    }
+
+    fn with_metadata(&self, mut tree: Tree<'a>) -> Tree<'a> {
+        if let Some(front_matter) = &self.front_matter {
+            tree.set_metadata(front_matter.metadata());
+            tree.offset_lines(front_matter.line_count());
+        }
+        tree
+    }
+}
@@ -0,0 +1,621 @@
+// Writes markdown back out in one canonical style, like rustfmt for docs: ATX headings, "-"
+// bullets, "*" emphasis, backtick fences and aligned tables, with paragraphs wrapped to a width.
+// The result always renders to the same html as the input
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::Config;
+use crate::ast::{Node, is_heading, lock};
+use crate::batch::{Job, plan_jobs};
+use crate::document::{Document, Format};
+use crate::inline::{Extensions, Footnotes, closing_backticks, normalize_emphasis, render_inline};
+use crate::lexer::{Token, TokenType, lex};
+use crate::toc::is_toc_marker;
+
+// Indent of the blocks after the first in a footnote definition
+const FOOTNOTE_INDENT: usize = 4;
+
+// Formats every markdown file named by <config> in place, or stdin to stdout.  With --check
+// nothing is written; the files that would change are listed instead
+pub fn run_fmt(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.reads_stdin() {
+        let mut contents: String = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        let formatted: String = format_markdown(&contents, config.width)?;
+        if config.check {
+            if formatted != contents {
+                return Err("stdin needs formatting".into());
+            }
+            return Ok(());
+        }
+        io::stdout().lock().write_all(formatted.as_bytes())?;
+        return Ok(());
+    }
+
+    // Directories that cannot be walked fail like files that cannot be formatted
+    let sources: Vec<Result<PathBuf, (PathBuf, String)>> = if config.is_batch() {
+        plan_jobs(config)?
+            .into_iter()
+            .filter_map(|job| match job {
+                Job::Convert(src, _) => Some(Ok(src)),
+                Job::Copy(..) => None,
+                Job::Unreadable(dir, reason) => Some(Err((dir, reason))),
+            })
+            .collect()
+    } else {
+        vec![Ok(PathBuf::from(&config.md_path))]
+    };
+
+    // A file that fails is reported and the others are still formatted
+    let mut unformatted: usize = 0;
+    let mut failed: usize = 0;
+    for source in sources {
+        let (path, result): (PathBuf, Result<bool, String>) = match source {
+            Ok(path) => {
+                let result: Result<bool, String> =
+                    format_file(&path, config).map_err(|e| e.to_string());
+                (path, result)
+            }
+            Err((dir, reason)) => (dir, Err(reason)),
+        };
+        match result {
+            Ok(changed) => unformatted += changed as usize,
+            Err(reason) => {
+                eprintln!("{}: {}", path.display(), reason);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} file(s) failed to format", failed).into());
+    }
+    if config.check && unformatted > 0 {
+        return Err(format!("{} file(s) need formatting", unformatted).into());
+    }
+    Ok(())
+}
+
+// Formats <source> in place, or with --check lists it instead; whether it needed formatting
+fn format_file(source: &Path, config: &Config) -> Result<bool, Box<dyn Error>> {
+    let contents: String = fs::read_to_string(source)?;
+    let formatted: String = format_markdown(&contents, config.width)?;
+    if formatted == contents {
+        return Ok(false);
+    }
+    if config.check {
+        println!("{}", source.display());
+    } else {
+        fs::write(source, formatted)?;
+    }
+    Ok(true)
+}
+
+// The canonical form of <contents>, with paragraphs wrapped at <width> columns; 0 never wraps.
+// Front matter is kept as written.  Fails rather than change what the markdown renders to
+pub fn format_markdown(contents: &str, width: usize) -> Result<String, &'static str> {
+    let document: Document = Document::parse(contents);
+    let tree = document.tree();
+    let mut output: String = String::new();
+
+    if let Some(front_matter) = document.front_matter() {
+        let fence: &str = match front_matter.format {
+            Format::Yaml => "---",
+            Format::Toml => "+++",
+        };
+        output.push_str(fence);
+        output.push('\n');
+        output.push_str(&front_matter.raw);
+        if !front_matter.raw.is_empty() && !front_matter.raw.ends_with('\n') {
+            output.push('\n');
+        }
+        output.push_str(fence);
+        output.push('\n');
+    }
+
+    let mut lines: Vec<String> = blocks(lock(&tree.root()).children(), width, false);
+    // Definitions go at the end, wherever they were written
+    for definition in tree.footnote_definitions() {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(footnote(&lock(&definition), width));
+    }
+
+    if !lines.is_empty() {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&lines.join("\n"));
+        output.push('\n');
+    }
+
+    if !same_html(contents, &output) {
+        return Err("Formatting would change the rendered html");
+    }
+    Ok(output)
+}
+
+// Lines of a sequence of blocks, separated by blank lines unless they are in a tight list
+fn blocks(children: &[Arc<Mutex<Node>>], width: usize, tight: bool) -> Vec<String> {
+    let mut output: Vec<String> = vec![];
+    // Lists right after a list of the same kind switch markers, or they would merge
+    let mut previous: Option<(String, bool)> = None;
+    for child in children {
+        let node = lock(child);
+        let tag: &str = node.get_tag();
+        let alternate: bool = match &previous {
+            Some((previous_tag, alternate)) if previous_tag == tag => !alternate,
+            _ => false,
+        };
+        if !output.is_empty() && !tight {
+            output.push(String::new());
+        }
+        output.extend(block(&node, width, alternate));
+        previous = Some((tag.to_string(), alternate));
+    }
+    output
+}
+
+fn block(node: &Node, width: usize, alternate: bool) -> Vec<String> {
+    let tag: &str = node.get_tag();
+    match node.literal() {
+        Some(_) if tag == "hr" => vec!["***".to_string()],
+        // Rewriting the emphasis in "[[_TOC_]]" would stop it being a marker
+        Some(text) if matches!(tag, "p" | "") && is_toc_marker(text) => {
+            vec![text.trim().to_string()]
+        }
+        Some(text) if is_heading(tag) => vec![heading(node, text)],
+        Some(text) => paragraph(text, width),
+        None => match tag {
+            "pre" => code_block(node),
+            "table" => table(node),
+            "blockquote" => {
+                let inner: Vec<String> = blocks(node.children(), narrow(width, 2), false);
+                if inner.is_empty() {
+                    return vec![">".to_string()];
+                }
+                inner
+                    .into_iter()
+                    .map(|line| {
+                        if line.is_empty() {
+                            ">".to_string()
+                        } else {
+                            format!("> {}", line)
+                        }
+                    })
+                    .collect()
+            }
+            "ul" | "ol" => list(node, width, alternate),
+            _ => blocks(node.children(), width, false),
+        },
+    }
+}
+
+// Always ATX, with the text on one line and any custom id after it
+fn heading(node: &Node, text: &str) -> String {
+    let level: usize = node.get_tag()[1..].parse().unwrap_or(1);
+    let text: String = text.lines().map(str::trim).collect::<Vec<&str>>().join(" ");
+    let mut output: String = "#".repeat(level);
+    for part in [
+        Some(normalized(&text)),
+        node.get_markup("id").map(|id| format!("{{#{}}}", id)),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|part| !part.is_empty())
+    {
+        output.push(' ');
+        output.push_str(&part);
+    }
+    output
+}
+
+// Paragraph text with its emphasis written with "*", wrapped at <width>.  Hard line breaks
+// stay where they are, and text with html in it is kept exactly as written
+fn paragraph(text: &str, width: usize) -> Vec<String> {
+    if has_html(text) {
+        return text.lines().map(str::to_string).collect();
+    }
+    let text: String = normalized(text);
+    let source: Vec<&str> = text.lines().collect();
+
+    let mut output: Vec<String> = vec![];
+    let mut segment: Vec<&str> = vec![];
+    for (i, line) in source.iter().enumerate() {
+        let last: bool = i + 1 == source.len();
+        match hard_break(line).filter(|_| !last) {
+            Some((content, line_break)) => {
+                segment.push(content);
+                let mut wrapped: Vec<String> = wrap(&segment.join("\n"), width);
+                if let Some(end) = wrapped.last_mut() {
+                    end.push_str(line_break);
+                }
+                output.extend(wrapped);
+                segment.clear();
+            }
+            None => segment.push(line),
+        }
+    }
+    if !segment.is_empty() {
+        output.extend(wrap(&segment.join("\n"), width));
+    }
+
+    // Keep the lines as written if wrapping changes the rendered text, e.g. inside a link
+    if same_inline(&text, &output.join("\n")) {
+        return output;
+    }
+    source
+        .iter()
+        .enumerate()
+        .map(
+            |(i, line)| match hard_break(line).filter(|_| i + 1 < source.len()) {
+                Some((content, line_break)) => format!("{}{}", content.trim_end(), line_break),
+                None => line.trim_end().to_string(),
+            },
+        )
+        .collect()
+}
+
+// Whether <text> may contain a tag, comment or other raw html, where whitespace can matter
+fn has_html(text: &str) -> bool {
+    text.match_indices('<').any(|(i, _)| {
+        text[i + 1..].starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'))
+    })
+}
+
+// A line ending in a hard break, split into its text and the break
+fn hard_break(line: &str) -> Option<(&str, &'static str)> {
+    if line.ends_with("  ") {
+        return Some((line.trim_end(), "  "));
+    }
+    let content: &str = line.strip_suffix('\\')?;
+    // An escaped backslash is not a break
+    let backslashes: usize = content.len() - content.trim_end_matches('\\').len();
+    backslashes.is_multiple_of(2).then_some((content, "\\"))
+}
+
+// Fills lines greedily up to <width> columns.  A word that would start a line as something
+// other than paragraph text, e.g. "-" or "#", stays at the end of the line before
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let words: Vec<String> = words(text);
+    if width == 0 {
+        return vec![words.join(" ")];
+    }
+
+    let mut lines: Vec<Vec<String>> = vec![];
+    let mut length: usize = 0;
+    for word in words {
+        let size: usize = word.chars().count();
+        match lines.last_mut() {
+            Some(line) if length + 1 + size <= width => {
+                line.push(word);
+                length += 1 + size;
+            }
+            _ => {
+                lines.push(vec![word]);
+                length = size;
+            }
+        }
+    }
+
+    let mut i: usize = 1;
+    while i < lines.len() {
+        if is_plain(&lines[i].join(" ")) {
+            i += 1;
+            continue;
+        }
+        let word: String = lines[i].remove(0);
+        lines[i - 1].push(word);
+        if lines[i].is_empty() {
+            lines.remove(i);
+        }
+    }
+    lines.into_iter().map(|line| line.join(" ")).collect()
+}
+
+// Whether <line> is read as a line of paragraph text, whatever comes before it
+fn is_plain(line: &str) -> bool {
+    matches!(
+        lex(line).as_slice(),
+        [Token {
+            token_type: TokenType::Literal,
+            ..
+        }]
+    )
+}
+
+// Splits text at whitespace, keeping code spans whole
+fn words(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output: Vec<String> = vec![];
+    let mut word: String = String::new();
+    let mut i: usize = 0;
+    while i < chars.len() {
+        match chars[i] {
+            ' ' | '\t' | '\n' => {
+                if !word.is_empty() {
+                    output.push(std::mem::take(&mut word));
+                }
+            }
+            '\\' => {
+                word.extend(chars.get(i..i + 2).unwrap_or(&chars[i..]));
+                i += 1;
+            }
+            '`' => {
+                let run: usize = chars[i..].iter().take_while(|&&c| c == '`').count();
+                let end: usize = closing_backticks(&chars, i + run, run).unwrap_or(i) + run;
+                for &c in &chars[i..end] {
+                    word.push(if c == '\n' { ' ' } else { c });
+                }
+                i = end;
+                continue;
+            }
+            c => word.push(c),
+        }
+        i += 1;
+    }
+    if !word.is_empty() {
+        output.push(word);
+    }
+    output
+}
+
+// A fence of backticks longer than any run inside, or of tildes if the info string has a
+// backtick
+fn code_block(node: &Node) -> Vec<String> {
+    let (code, info): (String, String) = match node.children().first() {
+        Some(code) => {
+            let code = lock(code);
+            (
+                code.literal().unwrap_or("").to_string(),
+                code.get_markup("info").unwrap_or("").to_string(),
+            )
+        }
+        None => (String::new(), String::new()),
+    };
+
+    let c: char = if info.contains('`') { '~' } else { '`' };
+    let longest: usize = code
+        .split(|other| other != c)
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence: String = c.to_string().repeat(3.max(longest + 1));
+
+    let mut output: Vec<String> = vec![format!("{}{}", fence, info)];
+    output.extend(code.lines().map(str::to_string));
+    output.push(fence);
+    output
+}
+
+// Columns padded to the same width, with the delimiter row showing the alignments
+fn table(node: &Node) -> Vec<String> {
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut aligns: Vec<String> = vec![];
+    for section in node.children() {
+        for row in lock(section).children() {
+            let row = lock(row);
+            let cells: Vec<String> = row
+                .children()
+                .iter()
+                .map(|cell| {
+                    let cell = lock(cell);
+                    if rows.is_empty() {
+                        aligns.push(cell.get_attribute("align").unwrap_or("").to_string());
+                    }
+                    normalized(cell.literal().unwrap_or("")).replace('|', "\\|")
+                })
+                .collect();
+            rows.push(cells);
+        }
+    }
+    if aligns.is_empty() {
+        return vec![];
+    }
+
+    let widths: Vec<usize> = (0..aligns.len())
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(|cell| cell.chars().count())
+                .fold(3, usize::max)
+        })
+        .collect();
+    let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+
+    let mut output: Vec<String> = vec![];
+    for (i, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .zip(&aligns)
+            .map(|((cell, &width), align)| {
+                let padding: usize = width - cell.chars().count();
+                let before: usize = match align.as_str() {
+                    "right" => padding,
+                    "center" => padding / 2,
+                    _ => 0,
+                };
+                format!(
+                    "{}{}{}",
+                    " ".repeat(before),
+                    cell,
+                    " ".repeat(padding - before)
+                )
+            })
+            .collect();
+        output.push(line(cells));
+        if i == 0 {
+            let delimiters: Vec<String> = widths
+                .iter()
+                .zip(&aligns)
+                .map(|(&width, align)| match align.as_str() {
+                    "left" => format!(":{}", "-".repeat(width - 1)),
+                    "right" => format!("{}:", "-".repeat(width - 1)),
+                    "center" => format!(":{}:", "-".repeat(width - 2)),
+                    _ => "-".repeat(width),
+                })
+                .collect();
+            output.push(line(delimiters));
+        }
+    }
+    output
+}
+
+// Items numbered up from the list's start, their blocks indented under the marker
+fn list(node: &Node, width: usize, alternate: bool) -> Vec<String> {
+    let start: usize = node
+        .get_attribute("start")
+        .and_then(|start| start.parse().ok())
+        .unwrap_or(1);
+    // Only the paragraphs of loose lists keep the p tag
+    let tight: bool = !node.children().iter().any(|item| {
+        lock(item)
+            .children()
+            .iter()
+            .any(|block| lock(block).literal().is_some() && lock(block).get_tag() == "p")
+    });
+
+    let mut output: Vec<String> = vec![];
+    for (i, item) in node.children().iter().enumerate() {
+        let marker: String = match (node.get_tag(), alternate) {
+            ("ul", false) => "-".to_string(),
+            ("ul", true) => "*".to_string(),
+            (_, false) => format!("{}.", start + i),
+            (_, true) => format!("{})", start + i),
+        };
+        if i > 0 && !tight {
+            output.push(String::new());
+        }
+        let indent: usize = marker.len() + 1;
+        output.extend(indent_under(
+            &marker,
+            indent,
+            blocks(lock(item).children(), narrow(width, indent), tight),
+        ));
+    }
+    output
+}
+
+// "[^label]:" and the definition's blocks; the first paragraph starts on the same line
+fn footnote(node: &Node, width: usize) -> Vec<String> {
+    let marker: String = format!("[^{}]:", node.get_markup("label").unwrap_or(""));
+    indent_under(
+        &marker,
+        FOOTNOTE_INDENT,
+        blocks(node.children(), narrow(width, FOOTNOTE_INDENT), false),
+    )
+}
+
+// <lines> with <marker> before the first and the rest indented by <indent>
+fn indent_under(marker: &str, indent: usize, lines: Vec<String>) -> Vec<String> {
+    if lines.is_empty() {
+        return vec![marker.to_string()];
+    }
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| match (i, line.is_empty()) {
+            (0, _) => format!("{} {}", marker, line),
+            (_, true) => line,
+            (_, false) => format!("{}{}", " ".repeat(indent), line),
+        })
+        .collect()
+}
+
+// Width left inside a container taking <columns>; at least one column, and 0 stays no wrapping
+fn narrow(width: usize, columns: usize) -> usize {
+    match width {
+        0 => 0,
+        _ => width.saturating_sub(columns).max(1),
+    }
+}
+
+// <text> with its emphasis written with "*", unless that would render differently
+fn normalized(text: &str) -> String {
+    let output: String = normalize_emphasis(text);
+    if same_inline(text, &output) {
+        output
+    } else {
+        text.to_string()
+    }
+}
+
+// Checked with and without the extensions, as either may be used to render
+fn variants() -> [Extensions; 2] {
+    [
+        Extensions::default(),
+        Extensions {
+            strikethrough: true,
+            highlight: true,
+            superscript: true,
+            ..Extensions::default()
+        },
+    ]
+}
+
+fn same_inline(a: &str, b: &str) -> bool {
+    variants().into_iter().all(|extensions| {
+        let render = |text: &str| render_inline(text, extensions, &mut Footnotes::default());
+        collapse(&render(a)) == collapse(&render(b))
+    })
+}
+
+fn same_html(a: &str, b: &str) -> bool {
+    variants().into_iter().all(|extensions| {
+        let render = |text: &str| {
+            let mut tree = Document::parse(text).tree();
+            tree.set_extensions(extensions);
+            tree.to_string()
+        };
+        collapse(&render(a)) == collapse(&render(b))
+    })
+}
+
+// Elements whose content is shown with its whitespace
+const RAW_TEXT: [&str; 4] = ["pre", "textarea", "script", "style"];
+
+// Html with each run of whitespace between words made a single space.  Tags, and the content
+// of elements that keep their whitespace, are left exactly as they are
+fn collapse(html: &str) -> String {
+    let mut output: String = String::new();
+    let mut rest: &str = html;
+    while !rest.is_empty() {
+        let start: usize = rest.find('<').unwrap_or(rest.len());
+        let mut space: bool = false;
+        for c in rest[..start].chars() {
+            if c.is_whitespace() {
+                space = true;
+                continue;
+            }
+            if std::mem::take(&mut space) {
+                output.push(' ');
+            }
+            output.push(c);
+        }
+        if space {
+            output.push(' ');
+        }
+        rest = &rest[start..];
+        if rest.is_empty() {
+            break;
+        }
+
+        let end: usize = rest.find('>').map_or(rest.len(), |end| end + 1);
+        let name: &str = rest[1..end]
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or("");
+        let end: usize = match RAW_TEXT.iter().find(|raw| name.eq_ignore_ascii_case(raw)) {
+            Some(raw) => rest.find(&format!("</{}", raw)).unwrap_or(rest.len()),
+            None => end,
+        };
+        output.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    output
+}
@@ -0,0 +1,846 @@
+// Inline content of a leaf: code spans, emphasis, links and images, raw html tags, backslash
+// escapes, entities and html escaping.
+// Delimiter runs are matched with the CommonMark "process emphasis" algorithm
+
+use crate::entities;
+use std::collections::HashMap;
+
+// Inline syntax and output beyond CommonMark, each off unless asked for
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Extensions {
+    // ~~del~~, and ~del~ unless subscript is on
+    pub strikethrough: bool,
+    // ==mark==
+    pub highlight: bool,
+    // ^sup^
+    pub superscript: bool,
+    // ~sub~
+    pub subscript: bool,
+    // A link to each heading, shown when hovering over it
+    pub permalinks: bool,
+    // Relative links to .md files point at the .html pages made from them
+    pub md_links: bool,
+}
+
+// Footnotes that are both defined and referenced, numbered in the order of their first
+// reference.  Also counts the references rendered so far, so that each gets its own id
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Footnotes {
+    labels: Vec<String>,
+    // Index of each label in <labels>
+    numbers: HashMap<String, usize>,
+    seen: Vec<usize>,
+}
+
+impl Footnotes {
+    // <labels> in the order they are numbered, from 1
+    pub fn build(labels: Vec<String>) -> Footnotes {
+        let mut numbers: HashMap<String, usize> = HashMap::new();
+        for (i, label) in labels.iter().enumerate() {
+            numbers.entry(label.clone()).or_insert(i);
+        }
+        Footnotes {
+            seen: vec![0; labels.len()],
+            numbers,
+            labels,
+        }
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    // Labels are matched without regard to case
+    fn number(&self, label: &str) -> Option<usize> {
+        self.numbers.get(&label.to_lowercase()).copied()
+    }
+}
+
+enum Piece {
+    // Already escaped html
+    Text(String),
+    Delim(Delim),
+    // A "[" or "![" that may open a link or image.  Inactive once it is inside a link, as
+    // links do not nest
+    Bracket { image: bool, active: bool },
+    // The html before and after the alt text of an image, which is rendered as plain text
+    ImageStart(String),
+    ImageEnd(String),
+}
+
+// A run of identical delimiter characters, e.g. "**"
+struct Delim {
+    ch: char,
+    // Position of the run in the text, in characters
+    start: usize,
+    // Characters not yet used by a span; these are printed as is
+    count: usize,
+    // Length of the run before any were used, for the "multiple of 3" rule
+    length: usize,
+    can_open: bool,
+    can_close: bool,
+    // Tags of the spans this run opens, innermost first
+    opens: Vec<&'static str>,
+    // Tags of the spans this run closes, innermost first
+    closes: Vec<&'static str>,
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut output: String = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+// Renders the text of a leaf as html
+pub fn render_inline(text: &str, extensions: Extensions, footnotes: &mut Footnotes) -> String {
+    let mut pieces: Vec<Piece> = scan(text, extensions, footnotes);
+    match_delimiters(&mut pieces, extensions);
+    render_pieces(&pieces)
+}
+
+fn render_pieces(pieces: &[Piece]) -> String {
+    let mut output: String = String::new();
+    // Images the pieces are in the alt text of; inside one, only text is written
+    let mut images: usize = 0;
+    for piece in pieces {
+        match piece {
+            Piece::ImageStart(html) => {
+                if images == 0 {
+                    output.push_str(html);
+                }
+                images += 1;
+            }
+            Piece::ImageEnd(html) => {
+                images -= 1;
+                if images == 0 {
+                    output.push_str(html);
+                }
+            }
+            Piece::Text(html) if images > 0 => {
+                output.push_str(&strip_tags(html).replace('"', "&quot;"));
+            }
+            Piece::Text(html) => output.push_str(html),
+            Piece::Delim(delim) if images > 0 => {
+                output.extend(std::iter::repeat_n(delim.ch, delim.count));
+            }
+            Piece::Delim(delim) => {
+                // Closers use up their leftmost characters, openers their rightmost
+                for tag in &delim.closes {
+                    output.push_str(&format!("</{}>", tag));
+                }
+                output.extend(std::iter::repeat_n(delim.ch, delim.count));
+                for tag in delim.opens.iter().rev() {
+                    output.push_str(&format!("<{}>", tag));
+                }
+            }
+            // Never closed
+            Piece::Bracket { image, .. } => output.push_str(if *image { "![" } else { "[" }),
+        }
+    }
+    output
+}
+
+// <text> with emphasis and strong emphasis made from "_" written with "*" instead.  Runs next
+// to a "*" are left alone, as they would merge with it
+pub fn normalize_emphasis(text: &str) -> String {
+    let mut pieces: Vec<Piece> = scan(text, Extensions::default(), &mut Footnotes::default());
+    match_delimiters(&mut pieces, Extensions::default());
+
+    let mut chars: Vec<char> = text.chars().collect();
+    let used = |tags: &[&str]| -> usize {
+        tags.iter()
+            .map(|tag| if *tag == "strong" { 2 } else { 1 })
+            .sum()
+    };
+    for piece in &pieces {
+        let Piece::Delim(delim) = piece else {
+            continue;
+        };
+        let end: usize = delim.start + delim.length;
+        let next_to_star: bool =
+            (delim.start > 0 && chars[delim.start - 1] == '*') || chars.get(end) == Some(&'*');
+        if delim.ch != '_' || next_to_star {
+            continue;
+        }
+        // Closers use up their leftmost characters, openers their rightmost
+        let closed: usize = used(&delim.closes);
+        let opened: usize = used(&delim.opens);
+        for c in &mut chars[delim.start..delim.start + closed] {
+            *c = '*';
+        }
+        for c in &mut chars[end - opened..end] {
+            *c = '*';
+        }
+    }
+    chars.into_iter().collect()
+}
+
+// The tag of a span made from <used> <ch> characters on each side, if there is one
+fn span_tag(ch: char, used: usize, extensions: Extensions) -> Option<&'static str> {
+    match (ch, used) {
+        ('*' | '_', 1) => Some("em"),
+        ('*' | '_', 2) => Some("strong"),
+        ('~', 1) if extensions.subscript => Some("sub"),
+        ('~', 1 | 2) if extensions.strikethrough => Some("del"),
+        ('=', 2) if extensions.highlight => Some("mark"),
+        ('^', 1) if extensions.superscript => Some("sup"),
+        _ => None,
+    }
+}
+
+fn is_delimiter(c: char, extensions: Extensions) -> bool {
+    match c {
+        '*' | '_' => true,
+        '~' => extensions.strikethrough || extensions.subscript,
+        '=' => extensions.highlight,
+        '^' => extensions.superscript,
+        _ => false,
+    }
+}
+
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+// Labels of the footnote references in <text>, in order, skipping code spans and escapes
+pub fn footnote_references(text: &str) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output: Vec<String> = Vec::new();
+
+    let mut unclosed: Unclosed = Unclosed::default();
+    let mut i: usize = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' {
+            i += 2;
+        } else if chars[i] == '`' {
+            let run: usize = run_length(&chars, i);
+            i = match find_backticks(&chars, i + run, run, &mut unclosed) {
+                Some(close) => close + run,
+                None => i + run,
+            };
+        } else if let Some((label, length)) = footnote_label(&chars[i..]) {
+            output.push(label.to_lowercase());
+            i += length;
+        } else {
+            i += 1;
+        }
+    }
+    output
+}
+
+// The label of the `[^label]` at the start of <chars>, and the length of the reference
+fn footnote_label(chars: &[char]) -> Option<(String, usize)> {
+    if !chars.starts_with(&['[', '^']) {
+        return None;
+    }
+    // Labels end at the first "]", and may not contain whitespace or "["
+    let end: usize = chars[2..]
+        .iter()
+        .position(|c| *c == ']' || *c == '[' || c.is_whitespace())
+        .map(|end| end + 2)
+        .filter(|end| chars[*end] == ']' && *end > 2)?;
+    let label: String = chars[2..end].iter().collect();
+    Some((label, end + 1))
+}
+
+// Splits <text> into escaped text, finished code spans and delimiter runs
+fn scan(text: &str, extensions: Extensions, footnotes: &mut Footnotes) -> Vec<Piece> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output: Vec<Piece> = Vec::new();
+    let mut plain: String = String::new();
+    let mut unclosed: Unclosed = Unclosed::default();
+    // Pieces that are brackets which may still open a link or image, innermost last
+    let mut brackets: Vec<usize> = Vec::new();
+    // Pieces that are delimiter runs outside any link or image made so far
+    let mut runs: Vec<usize> = Vec::new();
+
+    let mut i: usize = 0;
+    while i < chars.len() {
+        let c: char = chars[i];
+
+        if c == '<'
+            && let Some(length) = raw_html(&chars[i..], &mut unclosed)
+        {
+            // Passed through untouched
+            plain.extend(&chars[i..i + length]);
+            i += length;
+        } else if c == '\\' && chars.get(i + 1).is_some_and(char::is_ascii_punctuation) {
+            // Escaped characters never have markdown meaning
+            plain.push_str(&escape_html(&chars[i + 1].to_string()));
+            i += 2;
+        } else if c == '&'
+            && let Some((decoded, length)) = entity(&chars[i..])
+        {
+            plain.push_str(&escape_html(&decoded));
+            i += length;
+        } else if c == '['
+            && let Some((label, length)) = footnote_label(&chars[i..])
+            && let Some(index) = footnotes.number(&label)
+        {
+            // Later references to the same footnote get ids of their own
+            footnotes.seen[index] += 1;
+            let id: String = match footnotes.seen[index] {
+                1 => format!("fnref-{}", index + 1),
+                seen => format!("fnref-{}-{}", index + 1, seen),
+            };
+            plain.push_str(&format!(
+                "<sup class=\"footnote-ref\"><a href=\"#fn-{}\" id=\"{}\">{}</a></sup>",
+                index + 1,
+                id,
+                index + 1
+            ));
+            i += length;
+        } else if c == '[' || (c == '!' && chars.get(i + 1) == Some(&'[')) {
+            if !plain.is_empty() {
+                output.push(Piece::Text(std::mem::take(&mut plain)));
+            }
+            brackets.push(output.len());
+            output.push(Piece::Bracket {
+                image: c == '!',
+                active: true,
+            });
+            i += if c == '!' { 2 } else { 1 };
+        } else if c == ']'
+            && let Some(&opener) = brackets.last()
+        {
+            brackets.pop();
+            if !plain.is_empty() {
+                output.push(Piece::Text(std::mem::take(&mut plain)));
+            }
+            let image: bool = matches!(output[opener], Piece::Bracket { image: true, .. });
+            let active: bool = matches!(output[opener], Piece::Bracket { active: true, .. });
+            match link_target(&chars[i + 1..]).filter(|_| active) {
+                Some((destination, title, length)) => {
+                    let destination: String = match extensions.md_links {
+                        true => html_target(&destination),
+                        false => destination,
+                    };
+                    let href: String = escape_attribute(&encode_url(&destination));
+                    let title: String = title.map_or(String::new(), |title| {
+                        format!(" title=\"{}\"", escape_attribute(&title))
+                    });
+
+                    // The text is matched on its own, so that no span crosses the brackets
+                    let inner: Vec<usize> =
+                        runs.split_off(runs.partition_point(|&run| run < opener));
+                    match_runs(&mut output, &inner, extensions);
+                    for &run in &inner {
+                        if let Piece::Delim(delim) = &mut output[run] {
+                            delim.can_open = false;
+                            delim.can_close = false;
+                        }
+                    }
+                    if image {
+                        // The text between is the alt text
+                        output[opener] = Piece::ImageStart(format!("<img src=\"{}\" alt=\"", href));
+                        output.push(Piece::ImageEnd(format!("\"{} />", title)));
+                    } else {
+                        output[opener] = Piece::Text(format!("<a href=\"{}\"{}>", href, title));
+                        output.push(Piece::Text("</a>".to_string()));
+                        // Innermost first; past one made inactive before, all the rest were
+                        for &outer in brackets.iter().rev() {
+                            match &mut output[outer] {
+                                Piece::Bracket {
+                                    image: false,
+                                    active: false,
+                                } => break,
+                                Piece::Bracket {
+                                    image: false,
+                                    active,
+                                } => *active = false,
+                                _ => {}
+                            }
+                        }
+                    }
+                    i += 1 + length;
+                }
+                None => {
+                    plain.push(']');
+                    i += 1;
+                }
+            }
+        } else if c == '`' {
+            let run: usize = run_length(&chars, i);
+            match find_backticks(&chars, i + run, run, &mut unclosed) {
+                Some(close) => {
+                    let code: String = chars[i + run..close].iter().collect();
+                    plain.push_str(&format!("<code>{}</code>", escape_html(code_span(&code))));
+                    i = close + run;
+                }
+                None => {
+                    // No closing run of the same length; the backticks are literal
+                    plain.push_str(&"`".repeat(run));
+                    i += run;
+                }
+            }
+        } else if is_delimiter(c, extensions) {
+            let run: usize = run_length(&chars, i);
+            let before: char = if i == 0 { ' ' } else { chars[i - 1] };
+            let after: char = chars.get(i + run).copied().unwrap_or(' ');
+            let left_flanking: bool = !after.is_whitespace()
+                && (!is_punctuation(after) || before.is_whitespace() || is_punctuation(before));
+            let right_flanking: bool = !before.is_whitespace()
+                && (!is_punctuation(before) || after.is_whitespace() || is_punctuation(after));
+            // "_" may not open or close inside a word
+            let (can_open, can_close) = if c == '_' {
+                (
+                    left_flanking && (!right_flanking || is_punctuation(before)),
+                    right_flanking && (!left_flanking || is_punctuation(after)),
+                )
+            } else {
+                (left_flanking, right_flanking)
+            };
+
+            if !plain.is_empty() {
+                output.push(Piece::Text(std::mem::take(&mut plain)));
+            }
+            runs.push(output.len());
+            output.push(Piece::Delim(Delim {
+                ch: c,
+                start: i,
+                count: run,
+                length: run,
+                can_open,
+                can_close,
+                opens: vec![],
+                closes: vec![],
+            }));
+            i += run;
+        } else {
+            plain.push_str(&escape_html(&c.to_string()));
+            i += 1;
+        }
+    }
+
+    if !plain.is_empty() {
+        output.push(Piece::Text(plain));
+    }
+    output
+}
+
+// The destination and title of the inline link whose text ends just before <chars>, e.g.
+// `(page.html "Title")`, and the length of that part of it.  Both are unescaped
+fn link_target(chars: &[char]) -> Option<(String, Option<String>, usize)> {
+    if chars.first() != Some(&'(') {
+        return None;
+    }
+    let mut i: usize = skip_whitespace(chars, 1);
+
+    let destination: &[char] = if chars.get(i) == Some(&'<') {
+        // Anything but line endings and unescaped angle brackets
+        let start: usize = i + 1;
+        i = start;
+        loop {
+            match chars.get(i)? {
+                '>' => break,
+                '\n' | '<' => return None,
+                '\\' => i += 2,
+                _ => i += 1,
+            }
+        }
+        i += 1;
+        &chars[start..i - 1]
+    } else {
+        // No spaces or control characters, and parentheses only in balanced pairs
+        let start: usize = i;
+        let mut depth: usize = 0;
+        while let Some(&c) = chars.get(i) {
+            match c {
+                '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => i += 1,
+                '(' => depth += 1,
+                ')' if depth == 0 => break,
+                ')' => depth -= 1,
+                _ if c == ' ' || c.is_ascii_control() => break,
+                _ => {}
+            }
+            i += 1;
+        }
+        if depth > 0 {
+            return None;
+        }
+        &chars[start..i]
+    };
+
+    let before_title: usize = i;
+    i = skip_whitespace(chars, i);
+    let mut title: Option<String> = None;
+    if i > before_title
+        && let Some(close) = match chars.get(i) {
+            Some('"') => Some('"'),
+            Some('\'') => Some('\''),
+            Some('(') => Some(')'),
+            _ => None,
+        }
+    {
+        let start: usize = i + 1;
+        i = start;
+        loop {
+            match *chars.get(i)? {
+                c if c == close => break,
+                '(' if close == ')' => return None,
+                '\\' => i += 2,
+                _ => i += 1,
+            }
+        }
+        title = Some(unescape(&chars[start..i].iter().collect::<String>()));
+        i = skip_whitespace(chars, i + 1);
+    }
+
+    if chars.get(i) != Some(&')') {
+        return None;
+    }
+    let destination: String = unescape(&destination.iter().collect::<String>());
+    Some((destination, title, i + 1))
+}
+
+// Index of the first character from <from> on that is not a space, tab or line ending
+fn skip_whitespace(chars: &[char], from: usize) -> usize {
+    from + chars[from.min(chars.len())..]
+        .iter()
+        .take_while(|c| matches!(c, ' ' | '\t' | '\n'))
+        .count()
+}
+
+// <text> with backslash escapes and entities replaced by the characters they stand for
+pub fn unescape(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output: String = String::with_capacity(text.len());
+    let mut i: usize = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' && chars.get(i + 1).is_some_and(char::is_ascii_punctuation) {
+            output.push(chars[i + 1]);
+            i += 2;
+        } else if chars[i] == '&'
+            && let Some((decoded, length)) = entity(&chars[i..])
+        {
+            output.push_str(&decoded);
+            i += length;
+        } else {
+            output.push(chars[i]);
+            i += 1;
+        }
+    }
+    output
+}
+
+// Percent-encodes the characters of a url that may not appear in one as they are, leaving
+// any that are already encoded
+fn encode_url(url: &str) -> String {
+    let bytes: &[u8] = url.as_bytes();
+    let mut output: String = String::with_capacity(url.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        let encoded: bool = byte == b'%'
+            && bytes
+                .get(i + 1..i + 3)
+                .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        if encoded || byte.is_ascii_alphanumeric() || b";/?:@&=+$,-_.!~*'()#".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    output
+}
+
+// Escaped for use inside a double-quoted attribute
+fn escape_attribute(text: &str) -> String {
+    escape_html(text).replace('"', "&quot;")
+}
+
+// The text of <html> without its tags, e.g. for the alt text of an image
+fn strip_tags(html: &str) -> String {
+    let mut output: String = String::with_capacity(html.len());
+    let mut in_tag: bool = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => output.push(c),
+            _ => {}
+        }
+    }
+    output
+}
+
+// A relative link to a .md file, pointed at the .html page made from it.  Other links are
+// kept as they are
+pub fn html_target(target: &str) -> String {
+    let is_relative: bool =
+        !target.contains("://") && !target.starts_with('/') && !target.starts_with("mailto:");
+    let split: usize = target.find(['#', '?']).unwrap_or(target.len());
+    let (path, suffix) = target.split_at(split);
+
+    match path.strip_suffix(".md") {
+        Some(stem) if is_relative && !stem.is_empty() => format!("{stem}.html{suffix}"),
+        _ => target.to_string(),
+    }
+}
+
+// Decodes the entity or numeric character reference at the start of <chars>, e.g. "&copy;",
+// "&#169;" or "&#xA9;", returning the text and the length of the reference
+fn entity(chars: &[char]) -> Option<(String, usize)> {
+    // Longest entity name is 31 characters
+    let end: usize = chars
+        .iter()
+        .take(40)
+        .position(|c| *c == ';')
+        .filter(|end| *end > 0)?;
+    let name: String = chars[1..end].iter().collect();
+
+    let decoded: String = match name.strip_prefix('#') {
+        Some(number) => {
+            let (digits, radix, max_len) = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16, 6),
+                None => (number, 10, 7),
+            };
+            if !(1..=max_len).contains(&digits.len()) || !digits.chars().all(|c| c.is_digit(radix))
+            {
+                return None;
+            }
+            // Invalid code points become the replacement character
+            let code: u32 = u32::from_str_radix(digits, radix).ok()?;
+            match char::from_u32(code).filter(|c| *c != '\0') {
+                Some(c) => c.to_string(),
+                None => '\u{FFFD}'.to_string(),
+            }
+        }
+        None => entities::lookup(&name)?.to_string(),
+    };
+    Some((decoded, end + 1))
+}
+
+// Comments, processing instructions, CDATA and declarations; matched before tags
+const HTML_BLOCKS: [(&str, &str); 4] = [
+    ("<!--", "-->"),
+    ("<?", "?>"),
+    ("<![CDATA[", "]]>"),
+    ("<!", ">"),
+];
+
+// Closings found to be missing from the rest of the text, so that later openings do not
+// search for them again
+#[derive(Default)]
+struct Unclosed {
+    // Indexed like HTML_BLOCKS
+    blocks: [bool; 4],
+    // Double and single quotes inside a tag
+    quotes: [bool; 2],
+    // Lengths of backtick runs
+    backticks: Vec<usize>,
+}
+
+// Number of characters equal to chars[i] from i on
+fn run_length(chars: &[char], i: usize) -> usize {
+    chars[i..].iter().take_while(|&&x| x == chars[i]).count()
+}
+
+// Length of the html tag or comment at the start of <chars>, if there is one
+fn raw_html(chars: &[char], unclosed: &mut Unclosed) -> Option<usize> {
+    for (kind, (open, close)) in HTML_BLOCKS.into_iter().enumerate() {
+        let open: Vec<char> = open.chars().collect();
+        let close: Vec<char> = close.chars().collect();
+        if !chars.starts_with(&open) {
+            continue;
+        }
+        // Declarations start with a letter
+        if open.len() == 2 && open[1] == '!' && !chars.get(2).is_some_and(char::is_ascii_alphabetic)
+        {
+            return None;
+        }
+        if unclosed.blocks[kind] {
+            return None;
+        }
+        let end: Option<usize> =
+            (open.len()..chars.len()).find(|&i| chars[i..].starts_with(&close));
+        unclosed.blocks[kind] = end.is_none();
+        return end.map(|end| end + close.len());
+    }
+
+    let closing: bool = chars.get(1) == Some(&'/');
+    let mut i: usize = if closing { 2 } else { 1 };
+    if !chars.get(i).is_some_and(char::is_ascii_alphabetic) {
+        return None;
+    }
+    while chars
+        .get(i)
+        .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '-')
+    {
+        i += 1;
+    }
+    if !chars
+        .get(i)
+        .is_some_and(|c| c.is_whitespace() || *c == '/' || *c == '>')
+    {
+        return None;
+    }
+
+    // Attributes may contain anything inside quotes
+    let mut quote: Option<char> = None;
+    while let Some(&c) = chars.get(i) {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '>' => return Some(i + 1),
+            None if c == '<' || (closing && !c.is_whitespace()) => return None,
+            None if (c == '"' || c == '\'') && !closing => {
+                if unclosed.quotes[usize::from(c == '\'')] {
+                    return None;
+                }
+                quote = Some(c);
+            }
+            None => {}
+        }
+        i += 1;
+    }
+    if let Some(q) = quote {
+        unclosed.quotes[usize::from(q == '\'')] = true;
+    }
+    None
+}
+
+// Finds a run of exactly <length> backticks at or after <from>
+fn find_backticks(
+    chars: &[char],
+    from: usize,
+    length: usize,
+    unclosed: &mut Unclosed,
+) -> Option<usize> {
+    if unclosed.backticks.contains(&length) {
+        return None;
+    }
+    let close: Option<usize> = closing_backticks(chars, from, length);
+    if close.is_none() {
+        unclosed.backticks.push(length);
+    }
+    close
+}
+
+// Start of the first run of exactly <length> backticks at or after <from>, in chars or bytes
+pub(crate) fn closing_backticks<T: Copy + PartialEq + From<u8>>(
+    text: &[T],
+    from: usize,
+    length: usize,
+) -> Option<usize> {
+    let backtick: T = T::from(b'`');
+    let mut i: usize = from;
+    while i < text.len() {
+        let run: usize = text[i..].iter().take_while(|&&x| x == backtick).count();
+        if run == length {
+            return Some(i);
+        }
+        i += run.max(1);
+    }
+    None
+}
+
+// One space is stripped from each side, so that code spans can start or end with a backtick
+fn code_span(code: &str) -> &str {
+    if code.len() > 2 && code.starts_with(' ') && code.ends_with(' ') && !code.trim().is_empty() {
+        &code[1..code.len() - 1]
+    } else {
+        code
+    }
+}
+
+// Pairs each closing run with the nearest compatible opening run before it
+fn match_delimiters(pieces: &mut [Piece], extensions: Extensions) {
+    let runs: Vec<usize> = (0..pieces.len())
+        .filter(|&i| matches!(pieces[i], Piece::Delim(_)))
+        .collect();
+    match_runs(pieces, &runs, extensions);
+}
+
+// Like match_delimiters, for only the runs at the indices <runs>, in order
+fn match_runs(pieces: &mut [Piece], runs: &[usize], extensions: Extensions) {
+    // Runs that may still open a span, in order
+    let mut openers: Vec<usize> = Vec::new();
+    // Closers of each kind that found no opener; later ones of that kind only look past them
+    let mut openers_bottom: HashMap<(char, bool, usize), usize> = HashMap::new();
+
+    for &closer in runs {
+        while let Piece::Delim(delim) = &pieces[closer] {
+            if !delim.can_close || delim.count == 0 {
+                break;
+            }
+            let kind: (char, bool, usize) = match delim.ch {
+                '*' | '_' => (delim.ch, delim.can_open, delim.length % 3),
+                _ => (delim.ch, false, delim.count),
+            };
+            let bottom: usize = openers_bottom.get(&kind).copied().unwrap_or(0);
+            let Some(stacked) = find_opener(pieces, &openers, closer, bottom, extensions) else {
+                openers_bottom.insert(kind, closer);
+                break;
+            };
+            let opener: usize = openers[stacked];
+
+            let (before, after) = pieces.split_at_mut(closer);
+            let (Piece::Delim(open), Piece::Delim(close)) = (&mut before[opener], &mut after[0])
+            else {
+                break;
+            };
+            let used: usize = match open.ch {
+                '*' | '_' if open.count >= 2 && close.count >= 2 => 2,
+                '*' | '_' => 1,
+                // Other spans need the same number of characters on each side
+                _ => close.count,
+            };
+            let Some(tag) = span_tag(open.ch, used, extensions) else {
+                break;
+            };
+            open.count -= used;
+            close.count -= used;
+            open.opens.push(tag);
+            close.closes.push(tag);
+
+            // Runs between the pair can no longer match anything
+            openers.truncate(stacked + 1);
+            if open.count == 0 {
+                openers.pop();
+            }
+        }
+        if let Piece::Delim(delim) = &pieces[closer]
+            && delim.can_open
+            && delim.count > 0
+        {
+            openers.push(closer);
+        }
+    }
+}
+
+// Index in <openers> of the nearest run that <closer> can pair with, starting at or after
+// the piece <bottom>
+fn find_opener(
+    pieces: &[Piece],
+    openers: &[usize],
+    closer: usize,
+    bottom: usize,
+    extensions: Extensions,
+) -> Option<usize> {
+    let Piece::Delim(close) = &pieces[closer] else {
+        return None;
+    };
+    let from: usize = openers.partition_point(|&i| i < bottom);
+    openers[from..]
+        .iter()
+        .rposition(|&i| match &pieces[i] {
+            Piece::Delim(open) if open.ch == close.ch => {
+                if open.ch == '*' || open.ch == '_' {
+                    // A run that can both open and close only pairs up if the lengths allow it
+                    !((open.can_close || close.can_open)
+                        && (open.length + close.length) % 3 == 0
+                        && !(open.length % 3 == 0 && close.length % 3 == 0))
+                } else {
+                    open.count == close.count && span_tag(open.ch, open.count, extensions).is_some()
+                }
+            }
+            _ => false,
+        })
+        .map(|stacked| from + stacked)
+}
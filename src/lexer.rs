@@ -1,82 +1,492 @@
+use std::borrow::Cow;
 use std::error::Error;
-use std::fs;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
 
 use crate::Config;
+use crate::ast::MAX_NESTING;
+use crate::document::{Format, FrontMatter, MAX_FRONT_MATTER};
 use TokenType::*;
 
 pub enum TokenType {
     Prefix,
     Suffix,
     Literal,
+    // Source text that is not part of any literal, e.g. markers, indentation and line endings.
+    // Only produced by lex_lossless
+    Trivia,
 }
 
-pub struct Token {
+// Values borrow from the line they were lexed from where possible
+pub struct Token<'a> {
     pub token_type: TokenType,
-    pub value: String,
+    pub value: Cow<'a, str>,
 }
 
-pub fn run_lexer(config: &Config) -> Result<Vec<Token>, Box<dyn Error>> {
-    let contents: String = match fs::read_to_string(&config.md_path) {
-        Ok(text) => text,
-        Err(io_err) => return Err(Box::new(io_err)),
-    };
+impl Token<'_> {
+    // Detaches the token from its source text
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            token_type: self.token_type,
+            value: Cow::Owned(self.value.into_owned()),
+        }
+    }
+}
 
+// Tags produced by the "#" prefix, indexed by level
+const HEADINGS: [&str; 7] = ["", "h1", "h2", "h3", "h4", "h5", "h6"];
+
+// Produces tokens lazily, one input line at a time
+pub struct Lexer<R: BufRead> {
+    reader: R,
+    line: String,
+    // Tokens of the current line, in reverse
+    pending: Vec<Token<'static>>,
+    // Opening fence of the code block being read, whose lines are not markdown
+    fence: Option<String>,
+    error: Option<io::Error>,
+}
+
+impl<R: BufRead> Lexer<R> {
+    pub fn build(reader: R) -> Lexer<R> {
+        Lexer {
+            reader,
+            line: String::new(),
+            pending: Vec::new(),
+            fence: None,
+            error: None,
+        }
+    }
+
+    // Reads the front matter at the start of the input, so that it is not lexed.  Call before
+    // the first token is read; without a closing fence within MAX_FRONT_MATTER the lines read
+    // are lexed as markdown
+    pub fn front_matter(&mut self) -> io::Result<Option<FrontMatter<'static>>> {
+        let mut read: String = String::new();
+        if self.reader.read_line(&mut read)? == 0 {
+            return Ok(None);
+        }
+        if let Some(format) = Format::from_fence(&read) {
+            // Up to and including the closing fence, the end of the input, or the most front
+            // matter there can be
+            let fence: usize = read.len();
+            loop {
+                let start: usize = read.len();
+                if start - fence > MAX_FRONT_MATTER
+                    || self.reader.read_line(&mut read)? == 0
+                    || format.is_closing_fence(&read[start..])
+                {
+                    break;
+                }
+            }
+        }
+
+        if let Some((front_matter, _)) = FrontMatter::split(&read) {
+            return Ok(Some(front_matter.into_owned()));
+        }
+        let mut tokens: Vec<Token> = Vec::new();
+        for line in read.lines() {
+            lex_line(line, &mut self.fence, &mut tokens);
+        }
+        self.pending
+            .extend(tokens.into_iter().rev().map(Token::into_owned));
+        Ok(None)
+    }
+
+    // Reports the read error that ended the stream early, if any
+    pub fn finish(self) -> io::Result<()> {
+        match self.error {
+            Some(io_err) => Err(io_err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Lexer<Box<dyn BufRead>> {
+    // Streams the markdown file (or stdin) named by <config>
+    pub fn open(config: &Config) -> io::Result<Lexer<Box<dyn BufRead>>> {
+        let reader: Box<dyn BufRead> = if config.reads_stdin() {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(BufReader::new(File::open(&config.md_path)?))
+        };
+        Ok(Lexer::build(reader))
+    }
+}
+
+// Lines are read into a reused buffer, so streamed tokens own their text
+impl<R: BufRead> Iterator for Lexer<R> {
+    type Item = Token<'static>;
+
+    fn next(&mut self) -> Option<Token<'static>> {
+        while self.pending.is_empty() {
+            if self.error.is_some() {
+                return None;
+            }
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    // Same line endings as str::lines
+                    let line: &str = self.line.strip_suffix('\n').unwrap_or(&self.line);
+                    let line: &str = line.strip_suffix('\r').unwrap_or(line);
+                    let mut tokens: Vec<Token> = Vec::new();
+                    lex_line(line, &mut self.fence, &mut tokens);
+                    self.pending
+                        .extend(tokens.into_iter().rev().map(Token::into_owned));
+                }
+                Err(io_err) => self.error = Some(io_err),
+            }
+        }
+        self.pending.pop()
+    }
+}
+
+pub fn run_lexer(config: &Config) -> Result<Vec<Token<'static>>, Box<dyn Error>> {
+    let mut lexer: Lexer<Box<dyn BufRead>> = Lexer::open(config)?;
+    let output: Vec<Token<'static>> = lexer.by_ref().collect();
+    lexer.finish()?;
+    Ok(output)
+}
+
+// Tokenizes markdown text that is already in memory; tokens borrow from <contents>
+pub fn lex(contents: &str) -> Vec<Token<'_>> {
+    lex_iter(contents).collect()
+}
+
+// Like lex, but each line is only tokenized once the tokens before it have been taken
+pub fn lex_iter(contents: &str) -> impl Iterator<Item = Token<'_>> {
+    let mut fence: Option<String> = None;
+    contents.lines().flat_map(move |line| {
+        let mut tokens: Vec<Token> = Vec::new();
+        lex_line(line, &mut fence, &mut tokens);
+        tokens
+    })
+}
+
+// Like lex, but every byte the other tokens leave out is kept as trivia, so that joining the
+// literals and trivia in order gives back <contents> exactly
+pub fn lex_lossless(contents: &str) -> Vec<Token<'_>> {
     let mut output: Vec<Token> = Vec::new();
-    for line in contents.lines() {
-        let words: Vec<&str> = line.trim().split(" ").collect();
+    let mut fence: Option<String> = None;
+    for raw in contents.split_inclusive('\n') {
+        // Same line endings as the streaming lexer
+        let line: &str = raw.strip_suffix('\n').unwrap_or(raw);
+        let line: &str = line.strip_suffix('\r').unwrap_or(line);
+        let mut tokens: Vec<Token> = Vec::new();
+        lex_line(line, &mut fence, &mut tokens);
 
-        if words.len() <= 1 {
-            if line == "" {
-                output.push(Token {
-                    token_type: Suffix,
-                    value: String::from("empty_line"),
-                })
-            } else if line == "```" {
-                output.push(Token {
-                    token_type: Suffix,
-                    value: String::from("code_block"),
-                })
-            } else if line.chars().all(|c| c == '=') {
-                output.push(Token {
-                    token_type: Suffix,
-                    value: String::from("h1"),
-                })
-            } else if line.chars().all(|c| c == '-') {
-                output.push(Token {
-                    token_type: Suffix,
-                    value: String::from("h2"),
-                })
-            } else if line.chars().all(|c| c == '-' || c == '|') {
-                output.push(Token {
-                    token_type: Suffix,
-                    value: String::from("table"),
-                })
-            } else {
-                output.push(Token {
-                    token_type: Literal,
-                    value: String::from(line),
-                })
+        // Literals are slices of the line; whatever lies between them is trivia
+        let mut consumed: usize = 0;
+        for token in tokens {
+            if let (Literal, Cow::Borrowed(text)) = (&token.token_type, &token.value) {
+                let start: usize = (text.as_ptr() as usize).wrapping_sub(raw.as_ptr() as usize);
+                if (consumed..=raw.len()).contains(&start) {
+                    push_trivia(&raw[consumed..start], &mut output);
+                    consumed = start + text.len();
+                }
             }
+            output.push(token);
+        }
+        push_trivia(&raw[consumed..], &mut output);
+    }
+    output
+}
+
+fn push_trivia<'a>(text: &'a str, output: &mut Vec<Token<'a>>) {
+    if !text.is_empty() {
+        output.push(Token {
+            token_type: Trivia,
+            value: Cow::Borrowed(text),
+        });
+    }
+}
+
+// Tokenizes a single line, without its line ending.  <fence> is carried from line to line
+fn lex_line<'a>(line: &'a str, fence: &mut Option<String>, output: &mut Vec<Token<'a>>) {
+    let line: &str = lex_containers(line, fence.is_some(), output);
+    let trimmed: &str = line.trim();
+
+    if let Some(open) = fence {
+        // Only the closing fence means anything; indentation is kept
+        if closes_fence(trimmed, open) {
+            *fence = None;
+            output.push(Token {
+                token_type: Suffix,
+                value: Cow::Borrowed("code_block"),
+            });
         } else {
-            let prefix: &str = words[0];
-            if prefix.chars().all(|c| c == '#') {
-                let mut tag: String = String::from("h");
-                tag.push_str(prefix.len().to_string().as_str());
-                output.push(Token {
-                    token_type: Prefix,
-                    value: tag,
-                });
-                output.push(Token {
-                    token_type: Literal,
-                    value: String::from(&line[prefix.len()..]),
-                });
+            output.push(Token {
+                token_type: Literal,
+                value: Cow::Borrowed(line),
+            });
+        }
+        return;
+    }
+    let indent: usize = columns(&line[..line.len() - line.trim_start().len()]);
+    let line: &str = line.trim_start();
+
+    // Only whether there is more than one word is needed; no need to collect
+    let mut words = trimmed.split(" ");
+    words.next();
+
+    // Any more indentation would make it code
+    if let Some((level, text)) = atx_heading(trimmed).filter(|_| indent <= 3) {
+        output.push(Token {
+            token_type: Prefix,
+            value: Cow::Borrowed(HEADINGS[level]),
+        });
+        output.push(Token {
+            token_type: Literal,
+            value: Cow::Borrowed(text),
+        });
+        return;
+    }
+
+    // Indentation is not checked, as the list items the line is in are not known here
+    if let Some((open, info)) = opening_fence(trimmed) {
+        // "code_block", the fence and any info string
+        output.push(Token {
+            token_type: Suffix,
+            value: Cow::Owned(
+                format!("code_block {} {}", open, info)
+                    .trim_end()
+                    .to_string(),
+            ),
+        });
+        *fence = Some(open.to_string());
+        return;
+    }
+
+    if is_thematic_break(trimmed) && !trimmed.chars().all(|c| c == '-') {
+        output.push(Token {
+            token_type: Suffix,
+            value: Cow::Borrowed("hr"),
+        });
+        return;
+    }
+
+    if let Some(alignments) = delimiter_row(trimmed) {
+        // "table", the alignment of each column when any is given, then the row as written, as
+        // it is a row or paragraph text wherever it cannot start a table
+        output.push(Token {
+            token_type: Suffix,
+            value: if alignments.iter().all(|a| *a == "none") {
+                Cow::Owned(format!("table {}", trimmed))
             } else {
+                Cow::Owned(format!("table {} {}", alignments.join(" "), trimmed))
+            },
+        });
+        return;
+    }
+
+    if words.next().is_none() {
+        if trimmed.is_empty() {
+            output.push(Token {
+                token_type: Suffix,
+                value: Cow::Borrowed("empty_line"),
+            })
+        } else if indent <= 3 && trimmed.chars().all(|c| c == '=') {
+            // The underline as written follows, as it is text where there is no paragraph above
+            output.push(Token {
+                token_type: Suffix,
+                value: Cow::Owned(format!("h1 {}", trimmed)),
+            })
+        } else if indent <= 3 && trimmed.chars().all(|c| c == '-') {
+            // Under a paragraph it makes a heading; otherwise three or more are a break
+            output.push(Token {
+                token_type: Suffix,
+                value: Cow::Owned(if trimmed.len() >= 3 {
+                    format!("h2 hr {}", trimmed)
+                } else {
+                    format!("h2 {}", trimmed)
+                }),
+            })
+        } else {
+            output.push(Token {
+                token_type: Literal,
+                value: Cow::Borrowed(line),
+            })
+        }
+    } else {
+        output.push(Token {
+            token_type: Literal,
+            value: Cow::Borrowed(line),
+        });
+    }
+}
+
+// The fence opening a code block, three or more "`" or "~", and the info string after it
+fn opening_fence(line: &str) -> Option<(&str, &str)> {
+    let c: char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length: usize = line.len() - line.trim_start_matches(c).len();
+    let info: &str = line[length..].trim();
+    // A backtick in the info string would make it a code span
+    if length < 3 || (c == '`' && info.contains('`')) {
+        return None;
+    }
+    Some((&line[..length], info))
+}
+
+// The closing fence is at least as long as the opening one, and made of the same character
+fn closes_fence(line: &str, open: &str) -> bool {
+    let c: char = open.chars().next().unwrap_or('`');
+    line.len() >= open.len() && line.chars().all(|x| x == c)
+}
+
+// An ATX heading such as `## Title ##`: 1 to 6 "#", then a space unless the heading is empty.
+// Returns the level and the text, without any closing sequence
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let level: usize = line.bytes().take_while(|b| *b == b'#').count();
+    let rest: &str = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+
+    let text: &str = rest.trim_matches([' ', '\t']);
+    // The closing "#"s must follow a space, or be all there is
+    let unclosed: &str = text.trim_end_matches('#');
+    if unclosed.is_empty() {
+        Some((level, unclosed))
+    } else if unclosed.ends_with([' ', '\t']) {
+        Some((level, unclosed.trim_end_matches([' ', '\t'])))
+    } else {
+        Some((level, text))
+    }
+}
+
+// Width of leading whitespace, with tabs as 4 columns
+fn columns(whitespace: &str) -> usize {
+    whitespace
+        .chars()
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+// Strips the blockquote markers, list item markers, footnote labels and indentation that open a
+// line, pushing a prefix for each: "blockquote", "ul <width> <bullet>",
+// "ol <width> <start> <delimiter>", "footnote <label>" or "indent <columns>".  <width> is how
+// far the item's content is indented.  Inside code only quotes are markers, and past
+// MAX_NESTING markers the rest of the line is content
+fn lex_containers<'a>(mut line: &'a str, in_code: bool, output: &mut Vec<Token<'a>>) -> &'a str {
+    let mut markers: usize = 0;
+    loop {
+        let content: &str = line.trim_start_matches([' ', '\t']);
+        let indent: usize = columns(&line[..line.len() - content.len()]);
+        let marker: Option<(Cow<'a, str>, &'a str)> = match content.strip_prefix('>') {
+            _ if markers == MAX_NESTING => None,
+            Some(rest) => Some((
+                Cow::Borrowed("blockquote"),
+                rest.strip_prefix(' ').unwrap_or(rest),
+            )),
+            None if !in_code => footnote_definition(content).or_else(|| list_marker(content)),
+            None => None,
+        };
+
+        if indent > 0 && !content.is_empty() {
+            output.push(Token {
+                token_type: Prefix,
+                value: Cow::Owned(format!("indent {}", indent)),
+            });
+        }
+        match marker {
+            Some((value, rest)) => {
                 output.push(Token {
-                    token_type: Literal,
-                    value: String::from(line),
+                    token_type: Prefix,
+                    value,
                 });
+                line = rest;
+                markers += 1;
             }
+            None => return line,
         }
     }
-    Ok(output)
+}
+
+// The label of a footnote definition, `[^label]: text`, then its text
+fn footnote_definition(content: &str) -> Option<(Cow<'_, str>, &str)> {
+    let rest: &str = content.strip_prefix("[^")?;
+    let (label, text) = rest.split_once("]:")?;
+    if label.is_empty() || label.contains(char::is_whitespace) || label.contains(['[', ']']) {
+        return None;
+    }
+    Some((
+        Cow::Owned(format!("footnote {}", label)),
+        text.trim_start_matches(' '),
+    ))
+}
+
+// A list item marker, "-", "*", "+", "1." or "1)", then its content
+fn list_marker(content: &str) -> Option<(Cow<'_, str>, &str)> {
+    if is_thematic_break(content) {
+        return None;
+    }
+    let digits: usize = content.bytes().take_while(u8::is_ascii_digit).count();
+    let marker_len: usize = match content.as_bytes().get(digits) {
+        Some(b'-' | b'*' | b'+') if digits == 0 => 1,
+        Some(b'.' | b')') if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+
+    // At least one space after the marker; more than four means indented content
+    let after: &str = &content[marker_len..];
+    let spaces: usize = after.len() - after.trim_start_matches(' ').len();
+    let spaces: usize = match spaces {
+        0 => return None,
+        1..=4 => spaces,
+        _ => 1,
+    };
+    let width: usize = marker_len + spaces;
+    let value: String = if digits == 0 {
+        format!("ul {} {}", width, &content[..1])
+    } else {
+        let start: u64 = content[..digits].parse().unwrap_or(1);
+        format!("ol {} {} {}", width, start, &content[digits..marker_len])
+    };
+    Some((Cow::Owned(value), &after[spaces..]))
+}
+
+// Three or more of the same "-", "*" or "_", possibly spaced out
+fn is_thematic_break(content: &str) -> bool {
+    let mut marks = content.chars().filter(|c| *c != ' ' && *c != '\t');
+    match marks.next() {
+        Some(first @ ('-' | '*' | '_')) => {
+            let rest: Vec<char> = marks.collect();
+            rest.len() >= 2 && rest.iter().all(|c| *c == first)
+        }
+        _ => false,
+    }
+}
+
+// Recognises a table delimiter row such as `|:---|:---:|---:|`, returning the alignment of each
+// column: "left", "center", "right" or "none"
+fn delimiter_row(line: &str) -> Option<Vec<&'static str>> {
+    if !line.contains('|') {
+        return None;
+    }
+    let inner: &str = line.strip_prefix('|').unwrap_or(line);
+    let inner: &str = inner.strip_suffix('|').unwrap_or(inner);
+
+    let mut output: Vec<&'static str> = Vec::new();
+    for cell in inner.split('|') {
+        let cell: &str = cell.trim();
+        let (left, dashes) = match cell.strip_prefix(':') {
+            Some(rest) => (true, rest),
+            None => (false, cell),
+        };
+        let (right, dashes) = match dashes.strip_suffix(':') {
+            Some(rest) => (true, rest),
+            None => (false, dashes),
+        };
+        if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+            return None;
+        }
+        output.push(match (left, right) {
+            (true, true) => "center",
+            (true, false) => "left",
+            (false, true) => "right",
+            (false, false) => "none",
+        });
+    }
+    Some(output)
 }
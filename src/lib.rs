@@ -1,26 +1,105 @@
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
 pub mod ast;
+pub mod batch;
 pub mod config;
+pub mod document;
+mod entities;
+pub mod format;
+pub mod inline;
 pub mod lexer;
+pub mod serve;
+pub mod toc;
+pub mod watch;
 
-use ast::run_ast;
-use config::Config;
-use lexer::Token;
-use lexer::run_lexer;
+use batch::{Summary, run_batch};
+use config::{Command, Config};
+use document::Document;
+use format::run_fmt;
+use serve::run_serve;
+use toc::toc_markdown;
+use watch::run_watch;
 
 pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    // LEXER
-    let tokens: Vec<Token> = run_lexer(&config)?;
+    if config.command == Command::Serve {
+        return run_serve(&config);
+    }
 
-    // AST
-    let ast: ast::Tree = run_ast(tokens);
+    if config.command == Command::Toc {
+        return print_toc(&config);
+    }
 
-    // WRITE
-    let mut file: File = File::create(config.output_path)?;
-    write!(file, "{}", ast)?;
+    if config.command == Command::Fmt {
+        return run_fmt(&config);
+    }
 
+    if config.watch {
+        return run_watch(&config);
+    }
+
+    if config.is_batch() {
+        let summary: Summary = run_batch(&config)?;
+        println!("{}", summary);
+        if !summary.failed.is_empty() {
+            return Err(format!("{} file(s) failed to convert", summary.failed.len()).into());
+        }
+        return Ok(());
+    }
+
+    convert(&config)
+}
+
+// Converts the single markdown file (or stdin) named by <config>, writing the html as it is
+// built
+fn convert(config: &Config) -> Result<(), Box<dyn Error>> {
+    let source: String = read_input(config)?;
+    let mut writer: Box<dyn Write> = open_output(config)?;
+    Document::parse(&source).write_html(config.extensions, &mut writer)?;
+    if config.writes_stdout() {
+        writeln!(writer)?;
+    }
+    writer.flush()?;
     Ok(())
 }
+
+// Prints the outline of the markdown file (or stdin) named by <config> as a markdown list
+fn print_toc(config: &Config) -> Result<(), Box<dyn Error>> {
+    let source: String = read_input(config)?;
+    let ast: ast::Tree = Document::parse(&source).tree();
+    write_output(config, toc_markdown(&ast.toc(1, 6)).trim_end())
+}
+
+// Reads the markdown file (or stdin) named by <config> whole, so that tokens and the text of
+// the tree can borrow from it
+fn read_input(config: &Config) -> io::Result<String> {
+    if config.reads_stdin() {
+        io::read_to_string(io::stdin().lock())
+    } else {
+        fs::read_to_string(&config.md_path)
+    }
+}
+
+fn write_output(config: &Config, output: &str) -> Result<(), Box<dyn Error>> {
+    let mut writer: Box<dyn Write> = open_output(config)?;
+    if config.writes_stdout() {
+        writeln!(writer, "{}", output)?;
+    } else {
+        write!(writer, "{}", output)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Stdout, or the output file with its directory created
+fn open_output(config: &Config) -> io::Result<Box<dyn Write>> {
+    if config.writes_stdout() {
+        return Ok(Box::new(BufWriter::new(io::stdout().lock())));
+    }
+    if let Some(parent) = Path::new(&config.output_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(Box::new(BufWriter::new(File::create(&config.output_path)?)))
+}
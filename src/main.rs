@@ -6,7 +6,7 @@ fn main() {
     let config = match Config::build(env::args()) {
         Ok(val) => val,
         Err(e) => {
-            println!("Error parsing arguments: {e}");
+            eprintln!("Error parsing arguments: {e}");
             process::exit(1);
         }
     };
@@ -16,7 +16,7 @@ fn main() {
             process::exit(0);
         }
         Err(e) => {
-            println!("Parser error: {e}");
+            eprintln!("Parser error: {e}");
             process::exit(1);
         }
     }
@@ -0,0 +1,354 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::Config;
+use crate::ast::Tree;
+use crate::batch::{Job, plan_jobs};
+use crate::document::Document;
+use crate::inline::{Extensions, escape_html};
+use crate::watch::{POLL_INTERVAL, Watcher};
+
+// Endpoint streaming server-sent events; an event is pushed whenever a source changes
+const RELOAD_PATH: &str = "/__reload";
+
+// Injected into every rendered page
+const RELOAD_SCRIPT: &str =
+    "<script>new EventSource(\"/__reload\").onmessage = () => location.reload();</script>\n";
+
+// Files tried, in order, when a directory is requested
+const INDEX_FILES: [&str; 2] = ["index.md", "README.md"];
+
+// How long a client may take to send its request before the connection is dropped, so that
+// idle connections do not hold a thread each
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+// Serves config.md_path on localhost until the process is killed
+pub fn run_serve(config: &Config) -> Result<(), Box<dyn Error>> {
+    let root: PathBuf = PathBuf::from(&config.md_path);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()).into());
+    }
+
+    let listener: TcpListener = TcpListener::bind(("127.0.0.1", config.port))?;
+    eprintln!(
+        "Serving {} at http://{}/",
+        root.display(),
+        listener.local_addr()?
+    );
+
+    // Bumped by the watcher thread; each reload stream compares against its last value
+    let generation: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    {
+        let root: PathBuf = root.clone();
+        let generation: Arc<AtomicUsize> = Arc::clone(&generation);
+        thread::spawn(move || watch_sources(&root, &generation));
+    }
+
+    serve(listener, &root, generation, config.extensions);
+    Ok(())
+}
+
+// Answers each connection on its own thread
+pub fn serve(
+    listener: TcpListener,
+    root: &Path,
+    generation: Arc<AtomicUsize>,
+    extensions: Extensions,
+) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let root: PathBuf = root.to_path_buf();
+        let generation: Arc<AtomicUsize> = Arc::clone(&generation);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &root, &generation, extensions) {
+                eprintln!("Connection error: {e}");
+            }
+        });
+    }
+}
+
+fn watch_sources(root: &Path, generation: &AtomicUsize) {
+    // Listing in place yields every markdown source and nothing else
+    let config: Config = Config {
+        md_path: root.to_string_lossy().into_owned(),
+        output_path: root.to_string_lossy().into_owned(),
+        ..Config::default()
+    };
+    let mut watcher: Watcher = Watcher::build();
+    let mut primed: bool = false;
+
+    loop {
+        if let Ok(jobs) = plan_jobs(&config) {
+            let sources: Vec<&Path> = jobs.iter().map(Job::source).collect();
+            if !watcher.changed(&sources).is_empty() && primed {
+                generation.fetch_add(1, Ordering::Relaxed);
+            }
+            primed = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    root: &Path,
+    generation: &AtomicUsize,
+    extensions: Extensions,
+) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader: BufReader<&TcpStream> = BufReader::new(&stream);
+    let mut request_line: String = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not needed; read past them
+    let mut header: String = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let words: Vec<&str> = request_line.split_whitespace().collect();
+    let response: Response = match words[..] {
+        ["GET", RELOAD_PATH, _] => return stream_reloads(stream, generation),
+        ["GET", url_path, _] | ["HEAD", url_path, _] => respond(root, url_path, extensions),
+        _ => Response {
+            status: "405 Method Not Allowed",
+            headers: vec![],
+            body: b"Method not allowed".to_vec(),
+        },
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\n", response.status)?;
+    for (name, value) in &response.headers {
+        write!(stream, "{name}: {value}\r\n")?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    )?;
+    if words.first() != Some(&"HEAD") {
+        stream.write_all(&response.body)?;
+    }
+    Ok(())
+}
+
+// Holds the connection open, sending an event each time the generation changes
+fn stream_reloads(mut stream: TcpStream, generation: &AtomicUsize) -> Result<(), Box<dyn Error>> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n"
+    )?;
+
+    let mut seen: usize = generation.load(Ordering::Relaxed);
+    let mut idle: u32 = 0;
+    loop {
+        thread::sleep(Duration::from_millis(200));
+        let current: usize = generation.load(Ordering::Relaxed);
+        if current != seen {
+            seen = current;
+            stream.write_all(b"data: reload\n\n")?;
+        } else if idle >= 50 {
+            // Comment line; fails once the browser has gone away
+            idle = 0;
+            stream.write_all(b": ping\n\n")?;
+        }
+        idle += 1;
+    }
+}
+
+// Maps a request path onto <root>: markdown is rendered, anything else is sent as is
+pub fn respond(root: &Path, url_path: &str, extensions: Extensions) -> Response {
+    let Some(relative) = resolve(url_path) else {
+        return not_found();
+    };
+    let target: PathBuf = root.join(&relative);
+
+    if target.is_dir() {
+        // The slash goes on the path, before any query
+        let (path, query): (&str, &str) =
+            url_path.split_at(url_path.find('?').unwrap_or(url_path.len()));
+        if !path.ends_with('/') {
+            return Response {
+                status: "301 Moved Permanently",
+                headers: vec![("Location", format!("{path}/{query}"))],
+                body: vec![],
+            };
+        }
+        return match INDEX_FILES
+            .iter()
+            .map(|name| target.join(name))
+            .find(|p| p.is_file())
+        {
+            Some(index) => render_page(&index, extensions),
+            None => list_directory(&target, url_path),
+        };
+    }
+
+    // Links converted by batch mode point at .html; serve the source instead
+    let source: PathBuf = target.with_extension("md");
+    if target.extension().is_some_and(|ext| ext == "html") && !target.is_file() && source.is_file()
+    {
+        return render_page(&source, extensions);
+    }
+    if target.extension().is_some_and(|ext| ext == "md") && target.is_file() {
+        return render_page(&target, extensions);
+    }
+
+    match fs::read(&target) {
+        Ok(bytes) => Response {
+            status: "200 OK",
+            headers: vec![("Content-Type", content_type(&target).to_string())],
+            body: bytes,
+        },
+        Err(_) => not_found(),
+    }
+}
+
+// Turns a url path into a relative file path, refusing anything that climbs out of the root
+fn resolve(url_path: &str) -> Option<PathBuf> {
+    let path: &str = url_path.split(['?', '#']).next().unwrap_or("");
+    let decoded: String = percent_decode(path)?;
+
+    let mut output: PathBuf = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => output.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(output)
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes: &[u8] = text.as_bytes();
+    let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex: &str = text.get(i + 1..i + 3)?;
+            output.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            output.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(output).ok()
+}
+
+// Encodes every byte of a path segment but unreserved characters, so that a name with "?",
+// "#", ":" or quotes links to itself; the "/" ending a directory name is kept
+fn percent_encode(name: &str) -> String {
+    let mut output: String = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    output
+}
+
+fn render_page(path: &Path, extensions: Extensions) -> Response {
+    let contents: String = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            return Response {
+                status: "500 Internal Server Error",
+                headers: vec![],
+                body: e.to_string().into_bytes(),
+            };
+        }
+    };
+    let mut ast: Tree = Document::parse(&contents).tree();
+    ast.set_extensions(extensions);
+
+    let mut page: String = ast.to_string();
+    let split: usize = page.rfind("</html>").unwrap_or(page.len());
+    page.insert_str(split, RELOAD_SCRIPT);
+    html(page)
+}
+
+fn list_directory(dir: &Path, url_path: &str) -> Response {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let mut name: String = entry.file_name().to_string_lossy().into_owned();
+                if entry.path().is_dir() {
+                    name.push('/');
+                }
+                name
+            })
+            .filter(|name| !name.starts_with('.'))
+            .collect(),
+        Err(_) => return not_found(),
+    };
+    names.sort();
+
+    // Names come from the file system and the path from the request; neither is trusted
+    let mut page: String = format!("<html>\n<h1>{}</h1>\n<ul>\n", escape_html(url_path));
+    for name in names {
+        let href: String = percent_encode(&name);
+        let name: String = escape_html(&name);
+        page.push_str(&format!("<li><a href=\"{href}\">{name}</a></li>\n"));
+    }
+    page.push_str("</ul>\n");
+    page.push_str(RELOAD_SCRIPT);
+    page.push_str("</html>");
+    html(page)
+}
+
+fn html(page: String) -> Response {
+    Response {
+        status: "200 OK",
+        headers: vec![("Content-Type", "text/html; charset=utf-8".to_string())],
+        body: page.into_bytes(),
+    }
+}
+
+fn not_found() -> Response {
+    Response {
+        status: "404 Not Found",
+        headers: vec![("Content-Type", "text/plain".to_string())],
+        body: b"Not found".to_vec(),
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension: String = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "md" | "txt" => "text/plain; charset=utf-8",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
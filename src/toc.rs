@@ -0,0 +1,100 @@
+// Table of contents: the outline of a document's headings, as html or as markdown
+
+use std::ops::Range;
+
+use crate::ast::indent;
+use crate::inline::{Extensions, Footnotes, render_inline};
+
+// Paragraphs that are replaced by the table of contents
+const MARKERS: [&str; 2] = ["[[_TOC_]]", "<!-- toc -->"];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TocEntry {
+    // The heading as written, without any `{#custom-id}`
+    pub text: String,
+    pub level: usize,
+    // Id of the heading; empty if its text has nothing to make a slug from
+    pub slug: String,
+    // Lines of the source the heading spans, counting from 0
+    pub lines: Range<usize>,
+    // Headings of a higher level until the next heading of this level or lower
+    pub children: Vec<TocEntry>,
+}
+
+pub fn is_toc_marker(text: &str) -> bool {
+    MARKERS.contains(&text.trim())
+}
+
+// Nested lists of links to the headings, indented to sit at <depth>
+pub fn render_toc(
+    entries: &[TocEntry],
+    extensions: Extensions,
+    depth: usize,
+    tab_size: usize,
+) -> String {
+    let mut output: String = String::new();
+    toc_helper(&mut output, entries, extensions, depth, tab_size, true);
+    output
+}
+
+fn toc_helper(
+    builder: &mut String,
+    entries: &[TocEntry],
+    extensions: Extensions,
+    depth: usize,
+    tab_size: usize,
+    outermost: bool,
+) {
+    let indent = |depth: usize| indent(depth, tab_size);
+    builder.push_str(&indent(depth));
+    builder.push_str(if outermost {
+        "<ul class=\"toc\">\n"
+    } else {
+        "<ul>\n"
+    });
+    for entry in entries {
+        // Footnote references are not links here
+        let text: String = render_inline(&entry.text, extensions, &mut Footnotes::default());
+        builder.push_str(&format!("{}<li>\n", indent(depth + 1)));
+        if entry.slug.is_empty() {
+            builder.push_str(&format!("{}{}\n", indent(depth + 2), text));
+        } else {
+            builder.push_str(&format!(
+                "{}<a href=\"#{}\">{}</a>\n",
+                indent(depth + 2),
+                entry.slug,
+                text
+            ));
+        }
+        if !entry.children.is_empty() {
+            toc_helper(
+                builder,
+                &entry.children,
+                extensions,
+                depth + 2,
+                tab_size,
+                false,
+            );
+        }
+        builder.push_str(&format!("{}</li>\n", indent(depth + 1)));
+    }
+    builder.push_str(&format!("{}</ul>\n", indent(depth)));
+}
+
+// Nested markdown list of links to the headings, two spaces per level of nesting
+pub fn toc_markdown(entries: &[TocEntry]) -> String {
+    fn helper(builder: &mut String, entries: &[TocEntry], nesting: usize) {
+        for entry in entries {
+            builder.push_str(&"  ".repeat(nesting));
+            if entry.slug.is_empty() {
+                builder.push_str(&format!("- {}\n", entry.text));
+            } else {
+                builder.push_str(&format!("- [{}](#{})\n", entry.text, entry.slug));
+            }
+            helper(builder, &entry.children, nesting + 1);
+        }
+    }
+    let mut output: String = String::new();
+    helper(&mut output, entries, 0);
+    output
+}
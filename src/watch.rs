@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::Config;
+use crate::batch::{Job, plan_jobs};
+use crate::convert;
+
+// How often sources are checked for changes
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Remembers the last seen modification time of each watched file
+pub struct Watcher {
+    mtimes: HashMap<PathBuf, SystemTime>,
+}
+
+impl Watcher {
+    pub fn build() -> Watcher {
+        Watcher {
+            mtimes: HashMap::new(),
+        }
+    }
+
+    // Returns the paths whose mtime differs from the previous call; new files count as changed.
+    // Files that disappeared are forgotten, so they count as changed if they come back
+    pub fn changed(&mut self, paths: &[&Path]) -> Vec<PathBuf> {
+        let mut output: Vec<PathBuf> = Vec::new();
+        let mut seen: HashMap<PathBuf, SystemTime> = HashMap::new();
+        for path in paths {
+            let Ok(mtime) = fs::metadata(path).and_then(|meta| meta.modified()) else {
+                continue;
+            };
+            if self.mtimes.get(*path) != Some(&mtime) {
+                output.push(path.to_path_buf());
+            }
+            seen.insert(path.to_path_buf(), mtime);
+        }
+        self.mtimes = seen;
+        output
+    }
+}
+
+// Converts config.md_path, then keeps reconverting whatever changes.  Errors are reported and
+// watching carries on
+pub fn run_watch(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut watcher: Watcher = Watcher::build();
+    eprintln!("Watching {} for changes", config.md_path);
+    // Whether the last listing failed, so that the error is reported once
+    let mut unlisted: bool = false;
+
+    loop {
+        if config.is_batch() {
+            // The directory may be renamed or deleted while watched, and come back
+            let jobs: Vec<Job> = match plan_jobs(config) {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    if !unlisted {
+                        eprintln!("Error listing {}: {}", config.md_path, e);
+                    }
+                    unlisted = true;
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            unlisted = false;
+            let sources: Vec<&Path> = jobs.iter().map(Job::source).collect();
+            let changed: Vec<PathBuf> = watcher.changed(&sources);
+            for job in jobs
+                .iter()
+                .filter(|job| changed.iter().any(|p| p == job.source()))
+            {
+                report(job.source(), job.run(config.extensions));
+            }
+        } else {
+            let source: &Path = Path::new(&config.md_path);
+            if !watcher.changed(&[source]).is_empty() {
+                report(source, convert(config));
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn report(source: &Path, result: Result<(), Box<dyn Error>>) {
+    match result {
+        Ok(()) => eprintln!("Converted {}", source.display()),
+        Err(e) => eprintln!("Error converting {}: {}", source.display(), e),
+    }
+}
@@ -1,11 +1,18 @@
-use md_parser::ast::{Tree, run_ast};
-use md_parser::lexer::{Token, TokenType::*};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use md_parser::ast::{Tree, run_ast, slug, write_ast};
+use md_parser::document::Metadata;
+use md_parser::inline::Extensions;
+use md_parser::lexer::{Token, TokenType::*, lex};
 
 #[test]
 fn literal_one() {
     let token_vec: Vec<Token> = vec![Token {
         token_type: Literal,
-        value: String::from("text1"),
+        value: Cow::from("text1"),
     }];
     let output: Tree = run_ast(token_vec);
     assert_eq!(
@@ -21,19 +28,19 @@ fn literal_two() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("text1"),
+            value: Cow::from("text1"),
         },
         Token {
             token_type: Literal,
-            value: String::from("text2"),
+            value: Cow::from("text2"),
         },
     ];
     let output: Tree = run_ast(token_vec);
     assert_eq!(
         output.to_string().as_str(),
         "<html>
-    <p>text1</p>
-    <p>text2</p>
+    <p>text1
+text2</p>
 </html>"
     )
 }
@@ -43,18 +50,18 @@ fn header_prefix() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Prefix,
-            value: String::from("h1"),
+            value: Cow::from("h1"),
         },
         Token {
             token_type: Literal,
-            value: String::from("text2"),
+            value: Cow::from("text2"),
         },
     ];
     let output: Tree = run_ast(token_vec);
     assert_eq!(
         output.to_string().as_str(),
         "<html>
-    <h1>text2</h1>
+    <h1 id=\"text2\">text2</h1>
 </html>"
     )
 }
@@ -64,18 +71,18 @@ fn header_suffix() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("text2"),
+            value: Cow::from("text2"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("h1"),
+            value: Cow::from("h1"),
         },
     ];
     let output: Tree = run_ast(token_vec);
     assert_eq!(
         output.to_string().as_str(),
         "<html>
-    <h1>text2</h1>
+    <h1 id=\"text2\">text2</h1>
 </html>"
     )
 }
@@ -85,11 +92,11 @@ fn table_header() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("Header 1|Header 2|Header 3"),
+            value: Cow::from("Header 1|Header 2|Header 3"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("table"),
+            value: Cow::from("table"),
         },
     ];
     let output: Tree = run_ast(token_vec);
@@ -97,11 +104,13 @@ fn table_header() {
         output.to_string().as_str(),
         "<html>
     <table>
-        <tr>
-            <th>Header 1</th>
-            <th>Header 2</th>
-            <th>Header 3</th>
-        </tr>
+        <thead>
+            <tr>
+                <th>Header 1</th>
+                <th>Header 2</th>
+                <th>Header 3</th>
+            </tr>
+        </thead>
     </table>
 </html>"
     )
@@ -112,11 +121,11 @@ fn table_header2() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("Header1 |"),
+            value: Cow::from("Header1 |"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("table"),
+            value: Cow::from("table"),
         },
     ];
     let output: Tree = run_ast(token_vec);
@@ -124,9 +133,11 @@ fn table_header2() {
         output.to_string().as_str(),
         "<html>
     <table>
-        <tr>
-            <th>Header1 </th>
-        </tr>
+        <thead>
+            <tr>
+                <th>Header1</th>
+            </tr>
+        </thead>
     </table>
 </html>"
     )
@@ -137,11 +148,11 @@ fn table_header3() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("|Header1"),
+            value: Cow::from("|Header1"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("table"),
+            value: Cow::from("table"),
         },
     ];
     let output: Tree = run_ast(token_vec);
@@ -149,9 +160,11 @@ fn table_header3() {
         output.to_string().as_str(),
         "<html>
     <table>
-        <tr>
-            <th>Header1</th>
-        </tr>
+        <thead>
+            <tr>
+                <th>Header1</th>
+            </tr>
+        </thead>
     </table>
 </html>"
     )
@@ -162,23 +175,23 @@ fn table() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Literal,
-            value: String::from("Header 1|Header 2|Header 3"),
+            value: Cow::from("Header 1|Header 2|Header 3"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("table"),
+            value: Cow::from("table"),
         },
         Token {
             token_type: Literal,
-            value: String::from("1,1|1,2|1,3"),
+            value: Cow::from("1,1|1,2|1,3"),
         },
         Token {
             token_type: Literal,
-            value: String::from("2,1|2,2|"),
+            value: Cow::from("2,1|2,2|"),
         },
         Token {
             token_type: Literal,
-            value: String::from("|3,1|3,2|3,3|3,4|"),
+            value: Cow::from("|3,1|3,2|3,3|3,4|"),
         },
     ];
     let output: Tree = run_ast(token_vec);
@@ -186,26 +199,30 @@ fn table() {
         output.to_string().as_str(),
         "<html>
     <table>
-        <tr>
-            <th>Header 1</th>
-            <th>Header 2</th>
-            <th>Header 3</th>
-        </tr>
-        <tr>
-            <td>1,1</td>
-            <td>1,2</td>
-            <td>1,3</td>
-        </tr>
-        <tr>
-            <td>2,1</td>
-            <td>2,2</td>
-        </tr>
-        <tr>
-            <td>3,1</td>
-            <td>3,2</td>
-            <td>3,3</td>
-            <td>3,4</td>
-        </tr>
+        <thead>
+            <tr>
+                <th>Header 1</th>
+                <th>Header 2</th>
+                <th>Header 3</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td>1,1</td>
+                <td>1,2</td>
+                <td>1,3</td>
+            </tr>
+            <tr>
+                <td>2,1</td>
+                <td>2,2</td>
+                <td></td>
+            </tr>
+            <tr>
+                <td>3,1</td>
+                <td>3,2</td>
+                <td>3,3</td>
+            </tr>
+        </tbody>
     </table>
 </html>"
     )
@@ -216,87 +233,608 @@ fn combination() {
     let token_vec: Vec<Token> = vec![
         Token {
             token_type: Prefix,
-            value: String::from("h2"),
+            value: Cow::from("h2"),
         },
         Token {
             token_type: Literal,
-            value: String::from("Header"),
+            value: Cow::from("Header"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("empty_line"),
+            value: Cow::from("empty_line"),
         },
         Token {
             token_type: Literal,
-            value: String::from("Donec non massa quis est blandit volutpat. Donec sit amet."),
+            value: Cow::from("Donec non massa quis est blandit volutpat. Donec sit amet."),
         },
         Token {
             token_type: Literal,
-            value: String::from("Header 1|Header 2|Header 3"),
+            value: Cow::from("Header 1|Header 2|Header 3"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("table"),
+            value: Cow::from("table"),
         },
         Token {
             token_type: Literal,
-            value: String::from("1,1|1,2|1,3"),
+            value: Cow::from("1,1|1,2|1,3"),
         },
         Token {
             token_type: Literal,
-            value: String::from("2,1|2,2|"),
+            value: Cow::from("2,1|2,2|"),
         },
         Token {
             token_type: Literal,
-            value: String::from("|3,1|3,2|3,3|3,4|"),
+            value: Cow::from("|3,1|3,2|3,3|3,4|"),
         },
         Token {
             token_type: Suffix,
-            value: String::from("empty_line"),
+            value: Cow::from("empty_line"),
         },
         Token {
             token_type: Literal,
-            value: String::from("Nam vitae felis lectus. Sed sodales faucibus erat, a porttitor."),
+            value: Cow::from("Nam vitae felis lectus. Sed sodales faucibus erat, a porttitor."),
         },
         Token {
             token_type: Prefix,
-            value: String::from("h3"),
+            value: Cow::from("h3"),
         },
         Token {
             token_type: Literal,
-            value: String::from("Header 3"),
+            value: Cow::from("Header 3"),
         },
     ];
     let output: Tree = run_ast(token_vec);
     assert_eq!(
         output.to_string().as_str(),
         "<html>
-    <h2>Header</h2>
+    <h2 id=\"header\">Header</h2>
     <p>Donec non massa quis est blandit volutpat. Donec sit amet.</p>
     <table>
-        <tr>
-            <th>Header 1</th>
-            <th>Header 2</th>
-            <th>Header 3</th>
-        </tr>
-        <tr>
-            <td>1,1</td>
-            <td>1,2</td>
-            <td>1,3</td>
-        </tr>
-        <tr>
-            <td>2,1</td>
-            <td>2,2</td>
-        </tr>
-        <tr>
-            <td>3,1</td>
-            <td>3,2</td>
-            <td>3,3</td>
-            <td>3,4</td>
-        </tr>
+        <thead>
+            <tr>
+                <th>Header 1</th>
+                <th>Header 2</th>
+                <th>Header 3</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td>1,1</td>
+                <td>1,2</td>
+                <td>1,3</td>
+            </tr>
+            <tr>
+                <td>2,1</td>
+                <td>2,2</td>
+                <td></td>
+            </tr>
+            <tr>
+                <td>3,1</td>
+                <td>3,2</td>
+                <td>3,3</td>
+            </tr>
+        </tbody>
     </table>
     <p>Nam vitae felis lectus. Sed sodales faucibus erat, a porttitor.</p>
-    <h3>Header 3</h3>
+    <h3 id=\"header-3\">Header 3</h3>
+</html>"
+    )
+}
+
+#[test]
+fn table_alignment() {
+    let token_vec: Vec<Token> = vec![
+        Token {
+            token_type: Literal,
+            value: Cow::from("Left|Center|Right|None"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("table left center right none"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("1|2|3|4"),
+        },
+    ];
+    let output: Tree = run_ast(token_vec);
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <table>
+        <thead>
+            <tr>
+                <th align=\"left\">Left</th>
+                <th align=\"center\">Center</th>
+                <th align=\"right\">Right</th>
+                <th>None</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td align=\"left\">1</td>
+                <td align=\"center\">2</td>
+                <td align=\"right\">3</td>
+                <td>4</td>
+            </tr>
+        </tbody>
+    </table>
+</html>"
+    )
+}
+
+#[test]
+fn table_cells() {
+    let token_vec: Vec<Token> = vec![
+        Token {
+            token_type: Literal,
+            value: Cow::from("| a | `b|c` | d \\| e |"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("table"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("| *1* || 3 | 4 |"),
+        },
+    ];
+    let output: Tree = run_ast(token_vec);
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <table>
+        <thead>
+            <tr>
+                <th>a</th>
+                <th><code>b|c</code></th>
+                <th>d | e</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td><em>1</em></td>
+                <td></td>
+                <td>3</td>
+            </tr>
+        </tbody>
+    </table>
+</html>"
+    )
+}
+
+#[test]
+fn table_ends_at_heading() {
+    let token_vec: Vec<Token> = vec![
+        Token {
+            token_type: Literal,
+            value: Cow::from("a|b"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("table"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("1|2"),
+        },
+        Token {
+            token_type: Prefix,
+            value: Cow::from("h1"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("Heading"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("code_block"),
+        },
+    ];
+    let output: Tree = run_ast(token_vec);
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <table>
+        <thead>
+            <tr>
+                <th>a</th>
+                <th>b</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td>1</td>
+                <td>2</td>
+            </tr>
+        </tbody>
+    </table>
+    <h1 id=\"heading\">Heading</h1>
+    <pre>
+        <code></code>
+    </pre>
+</html>"
+    )
+}
+
+#[test]
+fn table_in_containers() {
+    let output: Tree = run_ast(lex(
+        "> a|b\n> -|-\n> 1|2\n\n- item\n  | x |\n  |---|\n- next\n",
+    ));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <blockquote>
+        <table>
+            <thead>
+                <tr>
+                    <th>a</th>
+                    <th>b</th>
+                </tr>
+            </thead>
+            <tbody>
+                <tr>
+                    <td>1</td>
+                    <td>2</td>
+                </tr>
+            </tbody>
+        </table>
+    </blockquote>
+    <ul>
+        <li>
+            item
+            <table>
+                <thead>
+                    <tr>
+                        <th>x</th>
+                    </tr>
+                </thead>
+            </table>
+        </li>
+        <li>
+            next
+        </li>
+    </ul>
+</html>"
+    )
+}
+
+#[test]
+fn delimiter_row_in_table() {
+    // Another delimiter row is a row of the table, not the start of a new one
+    let output: Tree = run_ast(lex("a | b\n--|--\n1 | 2\n--|--\n5 | 6\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <table>
+        <thead>
+            <tr>
+                <th>a</th>
+                <th>b</th>
+            </tr>
+        </thead>
+        <tbody>
+            <tr>
+                <td>1</td>
+                <td>2</td>
+            </tr>
+            <tr>
+                <td>--</td>
+                <td>--</td>
+            </tr>
+            <tr>
+                <td>5</td>
+                <td>6</td>
+            </tr>
+        </tbody>
+    </table>
+</html>"
+    )
+}
+
+#[test]
+fn table_needs_paragraph() {
+    // Anywhere else a delimiter row is text, and the blocks before it stay
+    let output: Tree = run_ast(lex(
+        "- a\n- b\n-|-\n\n```\ncode\n```\n|--|--|\n\n> q\n\n|-|-|\n",
+    ));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <ul>
+        <li>
+            a
+        </li>
+        <li>
+            b
+        </li>
+    </ul>
+    <p>-|-</p>
+    <pre>
+        <code>code
+</code>
+    </pre>
+    <p>|--|--|</p>
+    <blockquote>
+        <p>q</p>
+    </blockquote>
+    <p>|-|-|</p>
+</html>"
+    )
+}
+
+#[test]
+fn table_header_rules() {
+    // The header needs as many cells as the delimiter row, and must be a paragraph line
+    let output: Tree = run_ast(lex(
+        "| abc | def |\n| --- |\n| bar |\n\n# Title\n|--|--|\n\n-|-\n",
+    ));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <p>| abc | def |
+| --- |
+| bar |</p>
+    <h1 id=\"title\">Title</h1>
+    <p>|--|--|</p>
+    <p>-|-</p>
+</html>"
+    )
+}
+
+#[test]
+fn short_rows_padded_in_proportion() {
+    // Empty cells are added to short rows while there are no more of them than bytes of table
+    let source: String = format!(
+        "{}\n{}\n{}",
+        "a|".repeat(100),
+        "-|".repeat(100),
+        "x\n".repeat(100)
+    );
+    let html: String = run_ast(lex(&source)).to_string();
+    assert_eq!(html.matches("<tr>").count(), 101);
+    assert_eq!(html.matches("<td>x</td>").count(), 100);
+    // The 400 bytes of header and delimiter row pad four rows, and the rows after them one more
+    assert_eq!(html.matches("<td></td>").count(), 99 * 5);
+}
+
+#[test]
+fn underline_without_paragraph() {
+    // An underline with no paragraph above is text, or a break when it is one
+    let output: Tree = run_ast(lex("- a\n==\n\n--\n\n```\ncode\n```\n=\n\n---\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <ul>
+        <li>
+            a
+        </li>
+    </ul>
+    <p>==</p>
+    <p>--</p>
+    <pre>
+        <code>code
+</code>
+    </pre>
+    <p>=</p>
+    <hr>
+</html>"
+    );
+
+    // Trailing spaces are allowed, but four spaces in it is a continuation line
+    let output: Tree = run_ast(lex("Foo\n===   \n\nBar\n    ---\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <h1 id=\"foo\">Foo</h1>
+    <p>Bar
+---</p>
+</html>"
+    )
+}
+
+#[test]
+fn header_suffix_multi_line() {
+    let token_vec: Vec<Token> = vec![
+        Token {
+            token_type: Literal,
+            value: Cow::from("Foo"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("bar"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("h2"),
+        },
+        Token {
+            token_type: Literal,
+            value: Cow::from("baz"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("empty_line"),
+        },
+        Token {
+            token_type: Suffix,
+            value: Cow::from("h1"),
+        },
+    ];
+    let output: Tree = run_ast(token_vec);
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <h2 id=\"foo-bar\">Foo
+bar</h2>
+    <p>baz</p>
 </html>"
     )
 }
+
+#[test]
+fn footnotes() {
+    let output: Tree = run_ast(lex("Text[^b] and[^a][^x].\n\n\
+         [^a]: First.\n\n\
+         [^unused]: Dropped.\n\n\
+         [^b]: Second\n    still second.\n\n    Another paragraph[^a].\n\nAfter.\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <p>Text<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup> and<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2\">2</a></sup>[^x].</p>
+    <p>After.</p>
+    <section class=\"footnotes\">
+        <ol>
+            <li id=\"fn-1\">
+                <p>Second
+still second.</p>
+                <p>Another paragraph<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2-2\">2</a></sup>.</p>
+                <a href=\"#fnref-1\" class=\"footnote-backref\">\u{21a9}</a>
+            </li>
+            <li id=\"fn-2\">
+                <p>First.</p>
+                <a href=\"#fnref-2\" class=\"footnote-backref\">\u{21a9}</a>
+            </li>
+        </ol>
+    </section>
+</html>"
+    )
+}
+
+#[test]
+fn heading_ids() {
+    assert_eq!(slug("Hello, World!"), "hello-world");
+    assert_eq!(slug(" `code` and *emphasis* "), "code-and-emphasis");
+    assert_eq!(slug("Über_alles - 2"), "über_alles---2");
+    // From the rendered text, so entities and tags are not part of it
+    assert_eq!(slug("A &amp; B"), "a--b");
+    assert_eq!(slug("<em>Tagged</em> `a<b`"), "tagged-ab");
+
+    let output: Tree = run_ast(lex(
+        "# Intro\n## Intro\n\nIntro\n---\n### Custom {#my-id}\n\
+         ### Not { #custom }\n> # Quoted\n",
+    ));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <h1 id=\"intro\">Intro</h1>
+    <h2 id=\"intro-1\">Intro</h2>
+    <h2 id=\"intro-2\">Intro</h2>
+    <h3 id=\"my-id\">Custom</h3>
+    <h3 id=\"not--custom-\">Not { #custom }</h3>
+    <blockquote>
+        <h1 id=\"quoted\">Quoted</h1>
+    </blockquote>
+</html>"
+    )
+}
+
+#[test]
+fn custom_ids_are_taken() {
+    let output: Tree = run_ast(lex("# X {#intro}\n# Intro\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <h1 id=\"intro\">X</h1>
+    <h1 id=\"intro-1\">Intro</h1>
+</html>"
+    )
+}
+
+#[test]
+fn repeated_and_empty_ids() {
+    let output: Tree = run_ast(lex("#\n# !\n# A {#x}\n# B {#x}\n# x\n"));
+    assert_eq!(
+        output.to_string().as_str(),
+        "<html>
+    <h1></h1>
+    <h1>!</h1>
+    <h1 id=\"x\">A</h1>
+    <h1 id=\"x-1\">B</h1>
+    <h1 id=\"x-2\">x</h1>
+</html>"
+    )
+}
+
+#[test]
+fn permalinks() {
+    let mut output: Tree = run_ast(lex("# Title\n"));
+    output.set_extensions(Extensions {
+        permalinks: true,
+        ..Extensions::default()
+    });
+    let html: String = output.to_string();
+    assert!(html.contains(
+        "<h1 id=\"title\"><a class=\"anchor\" href=\"#title\" aria-hidden=\"true\">#</a>Title</h1>"
+    ));
+    assert!(html.contains("<head>\n        <style>.anchor"));
+}
+
+// Writer whose bytes can be read while write_ast holds it
+#[derive(Clone, Default)]
+struct Shared(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn written(source: &str) -> String {
+    let mut writer: Vec<u8> = vec![];
+    write_ast(
+        lex(source),
+        Extensions::default(),
+        Metadata::default(),
+        &mut writer,
+    )
+    .unwrap();
+    String::from_utf8(writer).unwrap()
+}
+
+#[test]
+fn write_matches_display() {
+    let sources: [&str; 5] = [
+        "# A\n\n- x\n\n- y\n\nText\n===\n\n# A\n",
+        "a|b\n-|-\n1|2\n\n> quote\n> more\n\n    code\n",
+        "# A\n\nText[^1]\n\n[^1]: Note\n\n# B\n",
+        "# A\n\n[[_TOC_]]\n\n## B\n\n# A\n",
+        "",
+    ];
+    for source in sources {
+        assert_eq!(written(source), run_ast(lex(source)).to_string());
+    }
+}
+
+#[test]
+fn write_before_the_end() {
+    let writer: Shared = Shared::default();
+    let html: Shared = writer.clone();
+    let mut seen: Option<String> = None;
+    let tokens = lex("# Title\n\nText\n\nMore\n\n")
+        .into_iter()
+        .chain(std::iter::from_fn(|| {
+            seen.get_or_insert_with(|| String::from_utf8(html.0.borrow().clone()).unwrap());
+            None
+        }));
+    write_ast(
+        tokens,
+        Extensions::default(),
+        Metadata::default(),
+        &mut writer.clone(),
+    )
+    .unwrap();
+    // Blocks are written as the line after them starts; the last one may still become
+    // something else, e.g. a setext heading, so it waits for the end
+    assert_eq!(
+        seen.unwrap(),
+        "<html>\n    <h1 id=\"title\">Title</h1>\n    <p>Text</p>\n"
+    );
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::scratch;
+use md_parser::ast::Tree;
+use md_parser::batch::{Summary, run_batch};
+use md_parser::config::Config;
+
+fn config(md_path: &Path, output_path: &Path, jobs: usize) -> Config {
+    Config {
+        md_path: md_path.to_string_lossy().into_owned(),
+        output_path: output_path.to_string_lossy().into_owned(),
+        jobs,
+        ..Config::default()
+    }
+}
+
+#[test]
+fn convert_tree() {
+    let root: PathBuf = scratch("convert_tree");
+    let docs: PathBuf = root.join("docs");
+    let site: PathBuf = root.join("site");
+    fs::create_dir_all(docs.join("guide")).unwrap();
+    fs::write(docs.join("index.md"), "# Home\n[next](guide/intro.md)\n").unwrap();
+    fs::write(docs.join("guide/intro.md"), "Intro\n").unwrap();
+    fs::write(docs.join("guide/logo.png"), [0u8, 1, 2]).unwrap();
+    fs::write(docs.join(".hidden.md"), "skip me\n").unwrap();
+
+    let summary: Summary = run_batch(&config(&docs, &site, 2)).unwrap();
+    assert_eq!(summary.converted.len(), 2);
+    assert_eq!(summary.copied.len(), 1);
+    assert!(summary.failed.is_empty());
+
+    let index: String = fs::read_to_string(site.join("index.html")).unwrap();
+    assert!(index.contains("<a href=\"guide/intro.html\">next</a>"));
+    assert!(site.join("guide/intro.html").is_file());
+    assert_eq!(fs::read(site.join("guide/logo.png")).unwrap(), [0u8, 1, 2]);
+    assert!(!site.join(".hidden.html").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn report_failures() {
+    let root: PathBuf = scratch("report_failures");
+    let docs: PathBuf = root.join("docs");
+    fs::create_dir_all(&docs).unwrap();
+    fs::write(docs.join("good.md"), "fine\n").unwrap();
+    fs::write(docs.join("bad.md"), [0xffu8, 0xfe]).unwrap();
+
+    let summary: Summary = run_batch(&config(&docs, &root.join("site"), 2)).unwrap();
+    assert_eq!(summary.converted, vec![docs.join("good.md")]);
+    assert_eq!(summary.failed.len(), 1);
+    assert_eq!(summary.failed[0].0, docs.join("bad.md"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn skip_code_blocks() {
+    let root: PathBuf = scratch("skip_code_blocks");
+    let docs: PathBuf = root.join("docs");
+    fs::create_dir_all(&docs).unwrap();
+    fs::write(
+        docs.join("index.md"),
+        "[a](a.md) `[c](c.md)`\n\n```\n[b](b.md)\n```\n",
+    )
+    .unwrap();
+
+    run_batch(&config(&docs, &root.join("site"), 1)).unwrap();
+    let index: String = fs::read_to_string(root.join("site/index.html")).unwrap();
+    assert!(index.contains("<a href=\"a.html\">a</a>"));
+    assert!(index.contains("<code>[c](c.md)</code>"));
+    assert!(index.contains("[b](b.md)"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_directory() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root: PathBuf = scratch("unreadable_directory");
+    let docs: PathBuf = root.join("docs");
+    fs::create_dir_all(docs.join("locked")).unwrap();
+    fs::write(docs.join("locked/page.md"), "text\n").unwrap();
+    fs::write(docs.join("open.md"), "text\n").unwrap();
+    fs::set_permissions(docs.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+
+    // Permissions do not stop root
+    if fs::read_dir(docs.join("locked")).is_err() {
+        let summary: Summary = run_batch(&config(&docs, &root.join("site"), 1)).unwrap();
+        assert_eq!(summary.converted, vec![docs.join("open.md")]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, docs.join("locked"));
+    }
+
+    fs::set_permissions(docs.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_cycle() {
+    let root: PathBuf = scratch("symlink_cycle");
+    let docs: PathBuf = root.join("docs");
+    fs::create_dir_all(docs.join("guide")).unwrap();
+    fs::write(docs.join("guide/intro.md"), "text\n").unwrap();
+    std::os::unix::fs::symlink(&docs, docs.join("guide/back")).unwrap();
+
+    let summary: Summary = run_batch(&config(&docs, &root.join("site"), 1)).unwrap();
+    assert_eq!(summary.converted, vec![docs.join("guide/intro.md")]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn tree_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Tree>();
+}
+
+#[test]
+fn deterministic_across_threads() {
+    let root: PathBuf = scratch("deterministic");
+    let docs: PathBuf = root.join("docs");
+    for i in 0..40 {
+        let dir: PathBuf = docs.join(format!("part{}", i % 4));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(format!("page{i}.md")),
+            format!("# Page {i}\ntext {i}\n"),
+        )
+        .unwrap();
+    }
+    fs::write(docs.join("part0/broken.md"), [0xffu8]).unwrap();
+
+    let mut outputs: Vec<(Summary, Vec<String>)> = Vec::new();
+    for jobs in [1, 3, 8] {
+        let site: PathBuf = root.join(format!("site{jobs}"));
+        let summary: Summary = run_batch(&config(&docs, &site, jobs)).unwrap();
+        let pages: Vec<String> = summary
+            .converted
+            .iter()
+            .map(|src| {
+                let dest: PathBuf = site.join(src.strip_prefix(&docs).unwrap());
+                fs::read_to_string(dest.with_extension("html")).unwrap()
+            })
+            .collect();
+        outputs.push((summary, pages));
+    }
+
+    let (first, first_pages) = &outputs[0];
+    assert_eq!(first.converted.len(), 40);
+    for (summary, pages) in &outputs[1..] {
+        assert_eq!(summary.converted, first.converted);
+        assert_eq!(summary.failed, first.failed);
+        assert_eq!(pages, first_pages);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,128 @@
+mod common;
+
+use common::render;
+
+#[test]
+fn tilde_fence_with_info() {
+    // Only the first word of the info string names the language
+    assert_eq!(
+        render("~~~ rust extra\nlet x = 1;\n~~~\n"),
+        "<html>
+    <pre>
+        <code class=\"language-rust\">let x = 1;
+</code>
+    </pre>
+</html>"
+    );
+}
+
+#[test]
+fn info_string_escapes() {
+    assert_eq!(
+        render("``` f&ouml;\\*o\\\"\nx\n```\n"),
+        "<html>
+    <pre>
+        <code class=\"language-fö*o&quot;\">x
+</code>
+    </pre>
+</html>"
+    );
+}
+
+#[test]
+fn shorter_fence_does_not_close() {
+    assert_eq!(
+        render("````\n```\n````\n"),
+        "<html>
+    <pre>
+        <code>```
+</code>
+    </pre>
+</html>"
+    );
+}
+
+#[test]
+fn thematic_breaks() {
+    assert_eq!(
+        render("***\n___\n- - -\n"),
+        "<html>
+    <hr>
+    <hr>
+    <hr>
+</html>"
+    );
+}
+
+#[test]
+fn break_after_blank_line() {
+    // Not an underline, as the paragraph has ended
+    assert_eq!(
+        render("text\n\n---\n"),
+        "<html>
+    <p>text</p>
+    <hr>
+</html>"
+    );
+}
+
+#[test]
+fn continued_paragraph() {
+    assert_eq!(
+        render("one\ntwo\nthree\n"),
+        "<html>
+    <p>one
+two
+three</p>
+</html>"
+    );
+}
+
+#[test]
+fn setext_heading_takes_paragraph() {
+    assert_eq!(
+        render("one\ntwo\n---\n"),
+        "<html>
+    <h2 id=\"one-two\">one
+two</h2>
+</html>"
+    );
+}
+
+#[test]
+fn new_bullet_starts_list() {
+    assert_eq!(
+        render("- a\n* b\n"),
+        "<html>
+    <ul>
+        <li>
+            a
+        </li>
+    </ul>
+    <ul>
+        <li>
+            b
+        </li>
+    </ul>
+</html>"
+    );
+}
+
+#[test]
+fn new_delimiter_starts_list() {
+    assert_eq!(
+        render("1. a\n2) b\n"),
+        "<html>
+    <ol>
+        <li>
+            a
+        </li>
+    </ol>
+    <ol start=\"2\">
+        <li>
+            b
+        </li>
+    </ol>
+</html>"
+    );
+}
@@ -0,0 +1,353 @@
+// Helpers shared by the integration tests, and the runner for the spec conformance suites in
+// tests/spec/
+#![allow(dead_code)]
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use md_parser::ast::{Tree, run_ast};
+use md_parser::inline::Extensions;
+use md_parser::lexer::lex;
+
+pub struct Example {
+    pub markdown: String,
+    pub html: String,
+    pub example: usize,
+    pub section: String,
+}
+
+// Loads examples from a spec.json style file: an array of flat objects
+pub fn load_examples(path: &str) -> Vec<Example> {
+    let text: String = fs::read_to_string(path).expect("spec file is checked in");
+    let mut parser: JsonParser = JsonParser {
+        chars: text.chars().collect(),
+        pos: 0,
+    };
+
+    let mut output: Vec<Example> = Vec::new();
+    parser.expect('[');
+    while parser.peek() != Some(']') {
+        let mut example: Example = Example {
+            markdown: String::new(),
+            html: String::new(),
+            example: 0,
+            section: String::new(),
+        };
+        parser.expect('{');
+        while parser.peek() != Some('}') {
+            let key: String = parser.string();
+            parser.expect(':');
+            match key.as_str() {
+                "markdown" => example.markdown = parser.string(),
+                "html" => example.html = parser.string(),
+                "section" => example.section = parser.string(),
+                "example" => example.example = parser.number(),
+                _ => parser.skip_value(),
+            }
+            parser.comma();
+        }
+        parser.expect('}');
+        parser.comma();
+        output.push(example);
+    }
+    parser.expect(']');
+    output
+}
+
+// Just enough JSON for the spec files
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) {
+        assert_eq!(self.peek(), Some(c), "malformed json at {}", self.pos);
+        self.pos += 1;
+    }
+
+    fn comma(&mut self) {
+        if self.peek() == Some(',') {
+            self.pos += 1;
+        }
+    }
+
+    fn number(&mut self) -> usize {
+        self.peek();
+        let start: usize = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .expect("number")
+    }
+
+    fn skip_value(&mut self) {
+        match self.peek() {
+            Some('"') => {
+                self.string();
+            }
+            _ => {
+                self.number();
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> u32 {
+        let hex: String = self.chars[self.pos..self.pos + 4].iter().collect();
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).expect("unicode escape")
+    }
+
+    fn string(&mut self) -> String {
+        self.expect('"');
+        let mut output: String = String::new();
+        loop {
+            let c: char = self.chars[self.pos];
+            self.pos += 1;
+            match c {
+                '"' => return output,
+                '\\' => {
+                    let escaped: char = self.chars[self.pos];
+                    self.pos += 1;
+                    match escaped {
+                        'n' => output.push('\n'),
+                        't' => output.push('\t'),
+                        'r' => output.push('\r'),
+                        'b' => output.push('\u{8}'),
+                        'f' => output.push('\u{c}'),
+                        'u' => {
+                            let mut code: u32 = self.hex4();
+                            if (0xd800..0xdc00).contains(&code) {
+                                // Surrogate pair
+                                self.pos += 2;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (self.hex4() - 0xdc00);
+                            }
+                            output.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        other => output.push(other),
+                    }
+                }
+                other => output.push(other),
+            }
+        }
+    }
+}
+
+// Fresh scratch directory under the system temp dir
+pub fn scratch(name: &str) -> PathBuf {
+    let dir: PathBuf =
+        std::env::temp_dir().join(format!("md_parser_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Html of <source> with no extensions
+pub fn render(source: &str) -> String {
+    run_ast(lex(source)).to_string()
+}
+
+// Runs markdown through the lexer, tree builder and renderer; None if it panicked.  The panic
+// hook is left alone, since tests in the same binary run in parallel and share it
+pub fn render_example(markdown: &str, extensions: Extensions) -> Option<String> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut tree: Tree = run_ast(lex(markdown));
+        tree.set_extensions(extensions);
+        tree.to_string()
+    }))
+    .ok()
+}
+
+// Makes our output and the spec's comparable: drops the <html> wrapper, the ids of headings,
+// whitespace next to tags, and the "/" of self-closing tags. Code blocks are kept exactly, apart
+// from the indentation around their <code> tag
+pub fn normalize(html: &str) -> String {
+    let html: &str = html.trim();
+    let html: &str = html
+        .strip_prefix("<html>")
+        .and_then(|h| h.strip_suffix("</html>"))
+        .unwrap_or(html);
+
+    let mut output: String = String::with_capacity(html.len());
+    let mut rest: &str = html;
+    while let Some(start) = rest.find("<pre") {
+        let Some(end) = rest[start..]
+            .find("</pre>")
+            .map(|end| start + end + "</pre>".len())
+        else {
+            break;
+        };
+        push_collapsed(&mut output, &rest[..start]);
+        push_code_block(&mut output, &rest[start..end]);
+        rest = &rest[end..];
+    }
+    push_collapsed(&mut output, rest);
+    drop_heading_ids(&output.replace(" />", ">").replace("/>", ">"))
+}
+
+// Drops whitespace next to tags
+fn push_collapsed(output: &mut String, html: &str) {
+    let mut spaces: String = String::new();
+    for c in html.chars() {
+        if c.is_whitespace() {
+            spaces.push(c);
+            continue;
+        }
+        if c != '<' && !output.ends_with('>') {
+            output.push_str(&spaces);
+        }
+        spaces.clear();
+        output.push(c);
+    }
+}
+
+// Copies a <pre> element, only trimming between <pre> and <code> and between </code> and </pre>
+fn push_code_block(output: &mut String, block: &str) {
+    let open_end: usize = block.find('>').map_or(block.len(), |i| i + 1);
+    let (open, inner) = block.split_at(open_end);
+    let inner: &str = inner.strip_suffix("</pre>").unwrap_or(inner);
+    let inner: &str = match inner.trim_start().starts_with("<code") {
+        true => inner.trim_start(),
+        false => inner,
+    };
+    let inner: &str = match inner.trim_end().ends_with("</code>") {
+        true => inner.trim_end(),
+        false => inner,
+    };
+    output.push_str(open);
+    output.push_str(inner);
+    output.push_str("</pre>");
+}
+
+// The specs predate heading ids
+fn drop_heading_ids(html: &str) -> String {
+    let mut output: String = String::with_capacity(html.len());
+    let mut rest: &str = html;
+    while let Some(start) = rest.find(" id=\"") {
+        let tag_start: usize = rest[..start].rfind('<').unwrap_or(0);
+        let is_heading: bool = rest[tag_start..start].len() == 3
+            && rest[tag_start + 1..start].starts_with('h')
+            && rest.as_bytes()[start - 1].is_ascii_digit();
+        let end: usize = match rest[start + 5..].find('"') {
+            Some(end) if is_heading => start + 5 + end + 1,
+            _ => start + 1,
+        };
+        output.push_str(&rest[..if is_heading { start } else { end }]);
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+// Runs every example in <spec_path>, prints pass rates per section, and fails on any
+// difference from the allowlist in <known_failures_path>, showing the source, expected and
+// actual output of each new failure.  Set SHOW_FAILURES=1 to print every failing example,
+// or UPDATE_KNOWN_FAILURES=1 to rewrite the allowlist instead
+pub fn run_suite(spec_path: &str, known_failures_path: &str, extensions: Extensions) {
+    let examples: Vec<Example> = load_examples(spec_path);
+
+    let mut failures: BTreeSet<usize> = BTreeSet::new();
+    let mut outputs: Vec<Option<String>> = Vec::new();
+    // (section, passed, total) in spec order
+    let mut sections: Vec<(String, usize, usize)> = Vec::new();
+    for example in &examples {
+        let actual: Option<String> = render_example(&example.markdown, extensions);
+        let passed: bool = actual
+            .as_ref()
+            .is_some_and(|actual| normalize(actual) == normalize(&example.html));
+        if !passed {
+            failures.insert(example.example);
+        }
+        outputs.push(actual);
+        match sections.last_mut() {
+            Some((name, ok, total)) if *name == example.section => {
+                *ok += passed as usize;
+                *total += 1;
+            }
+            _ => sections.push((example.section.clone(), passed as usize, 1)),
+        }
+    }
+
+    println!("{}", spec_path);
+    for (name, passed, total) in &sections {
+        println!(
+            "{:<45} {:>4}/{:<4} {:>5.1}%",
+            name,
+            passed,
+            total,
+            100.0 * *passed as f64 / *total as f64
+        );
+    }
+    println!(
+        "{:<45} {:>4}/{:<4} {:>5.1}%",
+        "Total",
+        examples.len() - failures.len(),
+        examples.len(),
+        100.0 * (examples.len() - failures.len()) as f64 / examples.len() as f64
+    );
+
+    let describe = |number: &usize| -> String {
+        let index: usize = examples
+            .iter()
+            .position(|e| e.example == *number)
+            .unwrap_or(0);
+        let example: &Example = &examples[index];
+        format!(
+            "\nexample {} ({})\n--- markdown\n{}--- expected\n{}--- actual\n{}\n",
+            example.example,
+            example.section,
+            example.markdown,
+            example.html,
+            outputs[index].as_deref().unwrap_or("<panicked>")
+        )
+    };
+    if env::var_os("SHOW_FAILURES").is_some() {
+        for number in &failures {
+            println!("{}", describe(number));
+        }
+    }
+
+    if env::var_os("UPDATE_KNOWN_FAILURES").is_some() {
+        let mut text: String = String::from("# Examples expected to fail; one number per line\n");
+        let mut section: &str = "";
+        for example in examples.iter().filter(|e| failures.contains(&e.example)) {
+            if example.section != section {
+                section = &example.section;
+                text.push_str(&format!("# {}\n", section));
+            }
+            text.push_str(&format!("{}\n", example.example));
+        }
+        fs::write(known_failures_path, text).expect("allowlist is writable");
+        return;
+    }
+
+    let known: BTreeSet<usize> = fs::read_to_string(known_failures_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split('#').next()?.trim().parse().ok())
+        .collect();
+    let regressed: Vec<&usize> = failures.difference(&known).collect();
+    let fixed: Vec<&usize> = known.difference(&failures).collect();
+    assert!(
+        regressed.is_empty() && fixed.is_empty(),
+        "newly failing examples: {:?}\nnow passing, remove from {}: {:?}\n{}",
+        regressed,
+        known_failures_path,
+        fixed,
+        regressed.iter().map(|n| describe(n)).collect::<String>()
+    );
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::{normalize, run_suite};
+use md_parser::inline::Extensions;
+
+#[test]
+fn normalize_html() {
+    assert_eq!(
+        normalize("<html>\n    <h1>Title</h1>\n    <hr />\n</html>"),
+        normalize("<h1>Title</h1>\n<hr>\n")
+    );
+}
+
+#[test]
+fn normalize_keeps_code_blocks() {
+    assert_eq!(
+        normalize("<html>\n    <pre>\n        <code> a\n\n</code>\n    </pre>\n</html>"),
+        "<pre><code> a\n\n</code></pre>"
+    );
+    assert_ne!(
+        normalize("<pre><code>a\n</code></pre>"),
+        normalize("<pre><code>a</code></pre>")
+    );
+}
+
+#[test]
+fn commonmark_spec() {
+    run_suite(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/spec/commonmark.json"),
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/spec/commonmark_known_failures.txt"
+        ),
+        Extensions::default(),
+    );
+}
@@ -0,0 +1,201 @@
+use md_parser::config::{Command, Config};
+use md_parser::inline::Extensions;
+use std::env;
+use std::path::{Path, PathBuf};
+
+fn build(args: &[&str]) -> Result<Config, &'static str> {
+    Config::build(
+        std::iter::once("md_parser")
+            .chain(args.iter().copied())
+            .map(String::from),
+    )
+}
+
+#[test]
+fn positional_output() {
+    let config: Config = build(&["notes.md", "out.html"]).unwrap();
+    assert_eq!(config.md_path, "notes.md");
+    assert_eq!(config.output_path, "out.html");
+}
+
+#[test]
+fn output_flag() {
+    let config: Config = build(&["notes.md", "-o", "out.html"]).unwrap();
+    assert_eq!(config.output_path, "out.html");
+    assert!(!config.writes_stdout());
+}
+
+#[test]
+fn stdin_to_stdout() {
+    let config: Config = build(&["-"]).unwrap();
+    assert!(config.reads_stdin());
+    assert!(config.writes_stdout());
+}
+
+#[test]
+fn file_to_stdout() {
+    let config: Config = build(&["notes.md", "-o", "-"]).unwrap();
+    assert!(!config.reads_stdin());
+    assert!(config.writes_stdout());
+}
+
+#[test]
+fn stdin_to_file() {
+    let config: Config = build(&["-", "--output", "out.html"]).unwrap();
+    assert!(config.reads_stdin());
+    assert_eq!(config.output_path, "out.html");
+}
+
+#[test]
+fn bad_arguments() {
+    assert!(build(&["notes.md", "-o"]).is_err());
+    assert!(build(&["notes.md", "--verbose"]).is_err());
+    assert!(build(&["a.md", "b.html", "c.html"]).is_err());
+}
+
+#[test]
+fn default_output_keeps_directory() {
+    assert_eq!(
+        build(&["./docs/intro.md"]).unwrap().output_path,
+        "./docs/intro.html"
+    );
+    assert_eq!(
+        build(&["v1.2/notes.md"]).unwrap().output_path,
+        "v1.2/notes.html"
+    );
+    assert_eq!(
+        build(&["notes.v2.md"]).unwrap().output_path,
+        "notes.v2.html"
+    );
+    assert_eq!(build(&["CHANGELOG"]).unwrap().output_path, "CHANGELOG.html");
+}
+
+#[test]
+fn out_dir_mirrors_input() {
+    let config: Config = build(&["./docs/guide/intro.md", "--out-dir", "site"]).unwrap();
+    assert_eq!(
+        Path::new(&config.output_path),
+        Path::new("site/docs/guide/intro.html")
+    );
+
+    let config: Config = build(&["docs/../notes.md", "--out-dir", "site"]).unwrap();
+    assert_eq!(Path::new(&config.output_path), Path::new("site/notes.html"));
+
+    let absolute: PathBuf = env::current_dir().unwrap().join("docs/intro.md");
+    let config: Config = build(&[absolute.to_str().unwrap(), "--out-dir", "site"]).unwrap();
+    assert_eq!(
+        Path::new(&config.output_path),
+        Path::new("site/docs/intro.html")
+    );
+}
+
+#[test]
+fn out_dir_mirrors_directory_input() {
+    // A directory is mirrored by the same rule as a file, so each of its files lands where it
+    // would if converted on its own
+    let config: Config = build(&["./tests/common", "--out-dir", "site"]).unwrap();
+    assert_eq!(
+        Path::new(&config.output_path),
+        Path::new("site/tests/common")
+    );
+    let file: Config = build(&["tests/common/mod.md", "--out-dir", "site"]).unwrap();
+    assert_eq!(
+        Path::new(&file.output_path),
+        Path::new(&config.output_path).join("mod.html")
+    );
+
+    assert!(build(&["..", "--out-dir", "site"]).is_err());
+}
+
+#[test]
+fn out_dir_rejects_outside_input() {
+    assert!(build(&["../notes.md", "--out-dir", "site"]).is_err());
+    assert!(build(&["docs/../../notes.md", "--out-dir", "site"]).is_err());
+
+    let outside: PathBuf = env::current_dir().unwrap().join("../notes.md");
+    assert!(build(&[outside.to_str().unwrap(), "--out-dir", "site"]).is_err());
+    assert!(build(&["/elsewhere/notes.md", "--out-dir", "site"]).is_err());
+}
+
+#[test]
+fn out_dir_conflicts() {
+    assert!(build(&["notes.md", "-o", "out.html", "--out-dir", "site"]).is_err());
+    assert!(build(&["-", "--out-dir", "site"]).is_err());
+}
+
+#[test]
+fn jobs_flag() {
+    assert_eq!(build(&["docs", "-j", "4"]).unwrap().jobs, 4);
+    assert!(build(&["docs"]).unwrap().jobs >= 1);
+    assert!(build(&["docs", "-j", "0"]).is_err());
+    assert!(build(&["docs", "--jobs", "many"]).is_err());
+}
+
+#[test]
+fn watch_flag() {
+    assert!(build(&["notes.md", "--watch"]).unwrap().watch);
+    assert!(!build(&["notes.md"]).unwrap().watch);
+    assert!(build(&["-", "-w"]).is_err());
+}
+
+#[test]
+fn extension_flags() {
+    let config: Config = build(&["notes.md", "--strikethrough", "--superscript"]).unwrap();
+    assert!(config.extensions.strikethrough && config.extensions.superscript);
+    assert!(!config.extensions.highlight && !config.extensions.subscript);
+    assert_eq!(
+        build(&["notes.md"]).unwrap().extensions,
+        Extensions::default()
+    );
+    assert!(
+        build(&["serve", "docs", "--highlight"])
+            .unwrap()
+            .extensions
+            .highlight
+    );
+    assert!(
+        build(&["notes.md", "--permalinks"])
+            .unwrap()
+            .extensions
+            .permalinks
+    );
+}
+
+#[test]
+fn serve_command() {
+    let config: Config = build(&["serve", "docs", "--port", "9000"]).unwrap();
+    assert_eq!(config.command, Command::Serve);
+    assert_eq!(config.md_path, "docs");
+    assert_eq!(config.port, 9000);
+
+    let config: Config = build(&["serve"]).unwrap();
+    assert_eq!(config.md_path, ".");
+    assert!(build(&["serve", "docs", "-o", "site"]).is_err());
+}
+
+#[test]
+fn toc_command() {
+    let config: Config = build(&["toc", "README.md"]).unwrap();
+    assert_eq!(config.command, Command::Toc);
+    assert_eq!(config.md_path, "README.md");
+    assert!(config.writes_stdout());
+
+    let config: Config = build(&["toc", "README.md", "-o", "toc.md"]).unwrap();
+    assert_eq!(config.output_path, "toc.md");
+    assert!(build(&["toc", "README.md", "--watch"]).is_err());
+}
+
+#[test]
+fn fmt_command() {
+    let config: Config = build(&["fmt", "docs"]).unwrap();
+    assert_eq!(config.command, Command::Fmt);
+    assert_eq!(config.md_path, "docs");
+    assert!(!config.check);
+    assert_eq!(config.width, 80);
+
+    let config: Config = build(&["fmt", "--check", "--width", "0", "README.md"]).unwrap();
+    assert!(config.check);
+    assert_eq!(config.width, 0);
+    assert!(build(&["fmt", "README.md", "out.md"]).is_err());
+    assert!(build(&["fmt", "README.md", "--width"]).is_err());
+}
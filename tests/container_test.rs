@@ -0,0 +1,115 @@
+mod common;
+
+use common::render;
+use md_parser::ast::MAX_NESTING;
+
+#[test]
+fn nested_blockquotes() {
+    assert_eq!(
+        render("> outer\n> > inner\n\nafter\n"),
+        "<html>
+    <blockquote>
+        <p>outer</p>
+        <blockquote>
+            <p>inner</p>
+        </blockquote>
+    </blockquote>
+    <p>after</p>
+</html>"
+    );
+}
+
+#[test]
+fn tight_list() {
+    assert_eq!(
+        render("- one\n- two\n  - nested\n"),
+        "<html>
+    <ul>
+        <li>
+            one
+        </li>
+        <li>
+            two
+            <ul>
+                <li>
+                    nested
+                </li>
+            </ul>
+        </li>
+    </ul>
+</html>"
+    );
+}
+
+#[test]
+fn loose_list() {
+    assert_eq!(
+        render("* one\n\n* two\n"),
+        "<html>
+    <ul>
+        <li>
+            <p>one</p>
+        </li>
+        <li>
+            <p>two</p>
+        </li>
+    </ul>
+</html>"
+    );
+}
+
+#[test]
+fn ordered_start() {
+    assert_eq!(
+        render("3. three\n4. four\n"),
+        "<html>
+    <ol start=\"3\">
+        <li>
+            three
+        </li>
+        <li>
+            four
+        </li>
+    </ol>
+</html>"
+    );
+}
+
+#[test]
+fn code_in_list_item() {
+    // The item's indentation is not part of the code
+    assert_eq!(
+        render("- item\n\n  ```\n    code\n  ```\n"),
+        "<html>
+    <ul>
+        <li>
+            <p>item</p>
+            <pre>
+                <code>  code\n</code>
+            </pre>
+        </li>
+    </ul>
+</html>"
+    );
+}
+
+#[test]
+fn nesting_is_capped() {
+    // Past the deepest container allowed, the rest of the line is text
+    let html: String = render(&format!("{} x\n", ">".repeat(20_000)));
+    assert_eq!(html.matches("<blockquote>").count(), MAX_NESTING);
+    assert!(html.contains(&format!("<p>{} x</p>", "&gt;".repeat(20_000 - MAX_NESTING))));
+    let html: String = render(&format!("{}x\n", "- ".repeat(20_000)));
+    assert_eq!(html.matches("<li>").count(), MAX_NESTING);
+
+    // Lines that go one deeper each time stop at it too, and indentation stops growing
+    let source: String = (1..=100)
+        .map(|depth| format!("{} x\n", ">".repeat(depth)))
+        .collect();
+    let html: String = render(&source);
+    assert_eq!(html.matches("<blockquote>").count(), MAX_NESTING);
+    assert!(
+        html.lines()
+            .all(|line| line.len() - line.trim_start().len() <= 32)
+    );
+}
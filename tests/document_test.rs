@@ -0,0 +1,59 @@
+use md_parser::document::{Document, Format, Metadata};
+
+#[test]
+fn yaml_front_matter() {
+    let document: Document = Document::parse(
+        "---\ntitle: \"Hello: world\"\ndescription: A page\ntags:\n  - a\n# comment\n---\n# Heading\n",
+    );
+    let front_matter = document.front_matter().unwrap();
+    assert_eq!(front_matter.format, Format::Yaml);
+    assert_eq!(
+        front_matter.raw,
+        "title: \"Hello: world\"\ndescription: A page\ntags:\n  - a\n# comment\n"
+    );
+    assert_eq!(document.body(), "# Heading\n");
+
+    let metadata: Metadata = document.metadata();
+    assert_eq!(metadata.title(), Some("Hello: world"));
+    assert_eq!(metadata.description(), Some("A page"));
+    assert_eq!(metadata.entries().len(), 2);
+}
+
+#[test]
+fn toml_front_matter() {
+    let document: Document =
+        Document::parse("+++\ntitle = 'Notes'\ndraft = true\n[extra]\nkey = 1\n+++\ntext\n");
+    let metadata: Metadata = document.metadata();
+    assert_eq!(document.front_matter().unwrap().format, Format::Toml);
+    assert_eq!(metadata.title(), Some("Notes"));
+    assert_eq!(metadata.get("draft"), Some("true"));
+    assert_eq!(metadata.get("key"), None);
+    assert_eq!(document.body(), "text\n");
+}
+
+#[test]
+fn no_front_matter() {
+    for source in ["---\nno closing fence\n", "text\n---\nx\n---\n", ""] {
+        let document: Document = Document::parse(source);
+        assert!(document.front_matter().is_none());
+        assert_eq!(document.body(), source);
+        assert_eq!(document.metadata(), Metadata::default());
+    }
+}
+
+#[test]
+fn head_from_metadata() {
+    let document: Document =
+        Document::parse("---\ntitle: A & B\ndescription: Say \"hi\"\n---\n---\ntext\n");
+    assert_eq!(
+        document.tree().to_string(),
+        "<html>
+    <head>
+        <title>A &amp; B</title>
+        <meta name=\"description\" content=\"Say &quot;hi&quot;\">
+    </head>
+    <hr>
+    <p>text</p>
+</html>"
+    );
+}
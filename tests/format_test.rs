@@ -0,0 +1,116 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use common::scratch;
+use md_parser::config::{Command, Config};
+use md_parser::format::{format_markdown, run_fmt};
+
+#[test]
+fn canonical_style() {
+    let source: &str = "Title\n=====\nSome _emphasis_ and __strong__ text\nover two lines.\n\
+                        * one\n* two\n    + nested\n\n+ other\n\n~~~ rust\nlet x = 1;\n~~~\n\
+                        ___\n> quote\n";
+    assert_eq!(
+        format_markdown(source, 80).unwrap(),
+        "# Title\n\nSome *emphasis* and **strong** text over two lines.\n\n\
+         - one\n- two\n  - nested\n\n* other\n\n```rust\nlet x = 1;\n```\n\n***\n\n> quote\n"
+    );
+}
+
+#[test]
+fn wrapping() {
+    let source: &str =
+        "A line of text that is long enough to wrap, with `a code span` - and a dash.\n";
+    assert_eq!(
+        format_markdown(source, 30).unwrap(),
+        "A line of text that is long\nenough to wrap, with\n`a code span` - and a dash.\n"
+    );
+    // Nothing may start a line that would read as another block
+    assert_eq!(
+        format_markdown("one two - three\n", 8).unwrap(),
+        "one two -\nthree\n"
+    );
+    assert_eq!(
+        format_markdown("A long paragraph that stays on one line.\n", 0).unwrap(),
+        "A long paragraph that stays on one line.\n"
+    );
+}
+
+#[test]
+fn tables() {
+    let source: &str = "Name | Value\n:--- | ---:\na | 1000\nb\\|c | 2\n";
+    assert_eq!(
+        format_markdown(source, 80).unwrap(),
+        "| Name | Value |\n| :--- | ----: |\n| a    |  1000 |\n| b\\|c |     2 |\n"
+    );
+}
+
+#[test]
+fn round_trip_details() {
+    // Fences longer than the code inside, custom ids, list numbering and footnotes
+    let source: &str = "---\ntitle: Doc\n---\n## Setup {#install}\n\n\
+                        ````\n```\n````\n\n3) a\n4) b\n\nSee[^Note].\n\n[^Note]: Here.\n";
+    assert_eq!(
+        format_markdown(source, 80).unwrap(),
+        "---\ntitle: Doc\n---\n\n## Setup {#install}\n\n````\n```\n````\n\n\
+         3. a\n4. b\n\nSee[^Note].\n\n[^Note]: Here.\n"
+    );
+}
+
+#[test]
+fn idempotent() {
+    let source: &str = "Intro *text* with a [link](https://example.com \"title\") and more\n\
+                        words.\n\n1. first\n\n   still first\n2. second\n   > quoted\n";
+    let once: String = format_markdown(source, 24).unwrap();
+    assert_eq!(format_markdown(&once, 24).unwrap(), once);
+}
+
+#[test]
+fn toc_markers_as_written() {
+    assert_eq!(
+        format_markdown("# A\n\n[[_TOC_]]\n", 80).unwrap(),
+        "# A\n\n[[_TOC_]]\n"
+    );
+}
+
+#[test]
+fn html_as_written() {
+    for source in [
+        "<div>\nhi\n</div>\n",
+        "x <span\nclass=\"a\">y</span>\n",
+        "<textarea>\na   b\n</textarea>\n",
+    ] {
+        assert_eq!(format_markdown(source, 80).unwrap(), source);
+    }
+}
+
+#[test]
+fn refuses_to_change_html() {
+    // Indented, so a paragraph; written back as is it would be a heading
+    assert!(format_markdown("    # foo\n", 80).is_err());
+    // A heading goes on one line, which would change the tag
+    assert!(format_markdown("x <span\nclass=\"a\">\n---\n", 80).is_err());
+}
+
+#[test]
+fn directory_keeps_going() {
+    let root: PathBuf = scratch("fmt_keeps_going");
+    fs::write(root.join("a.md"), b"\xff\n").unwrap();
+    fs::write(root.join("b.md"), "Title\n=====\n").unwrap();
+    let config: Config = Config {
+        command: Command::Fmt,
+        md_path: root.to_string_lossy().into_owned(),
+        output_path: root.to_string_lossy().into_owned(),
+        width: 80,
+        ..Config::default()
+    };
+
+    // The file after the one that fails is still formatted, and the failure still counts
+    let error: String = run_fmt(&config).unwrap_err().to_string();
+    assert_eq!(error, "1 file(s) failed to format");
+    assert_eq!(fs::read_to_string(root.join("b.md")).unwrap(), "# Title\n");
+
+    fs::remove_dir_all(&root).unwrap();
+}
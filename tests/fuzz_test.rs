@@ -0,0 +1,222 @@
+// Deterministic stand-in for the fuzz targets in fuzz/: random and mutated inputs must
+// never panic, the html must stay proportional to the input, and a lossless tree must give
+// back its source
+use std::fs;
+use std::path::PathBuf;
+
+use md_parser::ast::{MAX_NESTING, Tree, run_ast};
+use md_parser::document::Document;
+use md_parser::format::format_markdown;
+use md_parser::inline::Extensions;
+use md_parser::lexer::{Lexer, lex};
+
+const ITERATIONS: usize = 5_000;
+
+// Fragments that reach the interesting branches of the lexer and tree builder
+const FRAGMENTS: [&str; 49] = [
+    "#",
+    "# ",
+    "###### ",
+    "####### ",
+    "=",
+    "===",
+    "-",
+    "---",
+    "|",
+    "---|---",
+    "|:--|",
+    "```",
+    "\n",
+    "\n\n",
+    " ",
+    "  ",
+    "\t",
+    "text",
+    "a|b",
+    "\\|",
+    "`",
+    "*",
+    "\u{3000}",
+    "é",
+    "&",
+    ";",
+    "&amp;",
+    "&#x",
+    "_",
+    "~",
+    "~~",
+    "==",
+    "^",
+    "> ",
+    "- ",
+    "+ ",
+    "1. ",
+    "2) ",
+    "~~~",
+    "***",
+    "\r\n",
+    "[^a]",
+    "[^a]: ",
+    "    ",
+    "{#id}",
+    "<!-- ",
+    "-->",
+    "<div>",
+    "[[_TOC_]]",
+];
+
+// Markers of containers, stacked by nested_input well past MAX_NESTING
+const CONTAINERS: [&str; 8] = [">", "> ", "- ", "* ", "1. ", "[^a]: ", "  ", "    "];
+
+// No byte of markdown makes more html than this.  The costliest is a `>` that opens and closes
+// a blockquote: 27 bytes of tags and at most 32 spaces of indentation before each
+const HTML_PER_BYTE: usize = 128;
+
+// Every extension on, so that their syntax is parsed too
+const ALL_EXTENSIONS: Extensions = Extensions {
+    strikethrough: true,
+    highlight: true,
+    superscript: true,
+    subscript: false,
+    permalinks: true,
+    md_links: true,
+};
+
+// xorshift64*; fixed seed so failures reproduce
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn seeds() -> Vec<String> {
+    let dir: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/render");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("seed corpus is checked in")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+fn random_input(rng: &mut Rng) -> String {
+    let mut output: String = String::new();
+    for _ in 0..rng.below(40) {
+        output.push_str(FRAGMENTS[rng.below(FRAGMENTS.len())]);
+    }
+    output
+}
+
+// Lines of deeply nested blockquotes, list items and footnotes
+fn nested_input(rng: &mut Rng) -> String {
+    let mut output: String = String::new();
+    for _ in 0..1 + rng.below(6) {
+        for _ in 0..rng.below(3 * MAX_NESTING) {
+            output.push_str(CONTAINERS[rng.below(CONTAINERS.len())]);
+        }
+        output.push_str(FRAGMENTS[rng.below(FRAGMENTS.len())]);
+        output.push('\n');
+    }
+    output
+}
+
+// Splices fragments into a seed at char boundaries
+fn mutate(rng: &mut Rng, seed: &str) -> String {
+    let mut output: String = seed.to_string();
+    for _ in 0..1 + rng.below(8) {
+        let mut at: usize = rng.below(output.len() + 1);
+        while !output.is_char_boundary(at) {
+            at -= 1;
+        }
+        match rng.below(3) {
+            0 => output.insert_str(at, FRAGMENTS[rng.below(FRAGMENTS.len())]),
+            1 => output.insert(at, '\n'),
+            _ => {
+                let mut end: usize = (at + rng.below(8)).min(output.len());
+                while !output.is_char_boundary(end) {
+                    end -= 1;
+                }
+                output.replace_range(at..end, "");
+            }
+        }
+    }
+    output
+}
+
+fn check(input: &str) {
+    let html: String = run_ast(lex(input)).to_string();
+    assert!(
+        html.len() <= 64 + HTML_PER_BYTE * input.len(),
+        "{} bytes of html for {:?}",
+        html.len(),
+        input
+    );
+
+    // Front matter, footnotes and the extensions
+    let document: Document = Document::parse(input);
+    for extensions in [Extensions::default(), ALL_EXTENSIONS] {
+        let mut tree: Tree = document.tree();
+        tree.set_extensions(extensions);
+        tree.to_string();
+    }
+
+    // The streaming lexer
+    let mut lexer = Lexer::build(input.as_bytes());
+    lexer.front_matter().unwrap();
+    run_ast(&mut lexer).to_string();
+    lexer.finish().unwrap();
+
+    assert_eq!(
+        document.lossless_tree().source().as_deref(),
+        Some(input),
+        "lossless tree of {:?}",
+        input
+    );
+    // Refusing is fine; panicking is not
+    let _ = format_markdown(input, 20);
+}
+
+#[test]
+fn seed_corpus() {
+    for seed in seeds() {
+        check(&seed);
+    }
+}
+
+#[test]
+fn random_inputs() {
+    let mut rng: Rng = Rng(0x5eed_f022);
+    for _ in 0..ITERATIONS {
+        check(&random_input(&mut rng));
+    }
+}
+
+#[test]
+fn nested_containers() {
+    let mut rng: Rng = Rng(0x4e57_f022);
+    for _ in 0..ITERATIONS {
+        check(&nested_input(&mut rng));
+    }
+}
+
+#[test]
+fn mutated_seeds() {
+    let seeds: Vec<String> = seeds();
+    let mut rng: Rng = Rng(0xdec0_f022);
+    for _ in 0..ITERATIONS {
+        let seed: &str = &seeds[rng.below(seeds.len())];
+        check(&mutate(&mut rng, seed));
+    }
+}
@@ -0,0 +1,19 @@
+mod common;
+
+use common::run_suite;
+use md_parser::inline::Extensions;
+
+#[test]
+fn gfm_spec() {
+    run_suite(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/spec/gfm.json"),
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/spec/gfm_known_failures.txt"
+        ),
+        Extensions {
+            strikethrough: true,
+            ..Extensions::default()
+        },
+    );
+}
@@ -0,0 +1,167 @@
+use md_parser::inline::{Extensions, Footnotes, render_inline};
+
+fn render(text: &str) -> String {
+    render_inline(text, Extensions::default(), &mut Footnotes::default())
+}
+
+#[test]
+fn backslash_escapes() {
+    assert_eq!(render("\\*not emphasis\\*"), "*not emphasis*");
+    assert_eq!(render("\\#not heading"), "#not heading");
+    assert_eq!(render("\\<b\\>"), "&lt;b&gt;");
+    // Only punctuation can be escaped
+    assert_eq!(render("\\a \\\\"), "\\a \\");
+    assert_eq!(render("`\\*`"), "<code>\\*</code>");
+}
+
+#[test]
+fn entities() {
+    assert_eq!(render("&copy; &#169; &#xA9;"), "© © ©");
+    assert_eq!(render("&amp; &lt;"), "&amp; &lt;");
+    assert_eq!(render("&ClockwiseContourIntegral; &ngE;"), "∲ ≧̸");
+    assert_eq!(render("&#0; &#x110000;"), "\u{FFFD} \u{FFFD}");
+    // Entities are text, never markdown
+    assert_eq!(render("&#42;foo&#42;"), "*foo*");
+}
+
+#[test]
+fn not_entities() {
+    assert_eq!(
+        render("&MadeUpEntity; &nbsp"),
+        "&amp;MadeUpEntity; &amp;nbsp"
+    );
+    assert_eq!(
+        render("&#87654321; &#+12; &x;"),
+        "&amp;#87654321; &amp;#+12; &amp;x;"
+    );
+    assert_eq!(render("`&copy;`"), "<code>&amp;copy;</code>");
+}
+
+#[test]
+fn extensions() {
+    let all: Extensions = Extensions {
+        strikethrough: true,
+        highlight: true,
+        superscript: true,
+        subscript: true,
+        ..Extensions::default()
+    };
+    assert_eq!(
+        render_inline(
+            "~~del~~ ==mark== x^2^ H~2~O",
+            all,
+            &mut Footnotes::default()
+        ),
+        "<del>del</del> <mark>mark</mark> x<sup>2</sup> H<sub>2</sub>O"
+    );
+    assert_eq!(
+        render_inline("**~~both~~**", all, &mut Footnotes::default()),
+        "<strong><del>both</del></strong>"
+    );
+    // Runs must be the same length on each side
+    assert_eq!(
+        render_inline("~~a~ ==b= ^^c^^", all, &mut Footnotes::default()),
+        "~~a~ ==b= ^^c^^"
+    );
+    let strikethrough: Extensions = Extensions {
+        strikethrough: true,
+        ..Extensions::default()
+    };
+    assert_eq!(
+        render_inline("~a~ ==b==", strikethrough, &mut Footnotes::default()),
+        "<del>a</del> ==b=="
+    );
+    // Strict CommonMark
+    assert_eq!(render("~~a~~ ==b== ^c^"), "~~a~~ ==b== ^c^");
+}
+
+#[test]
+fn links() {
+    assert_eq!(
+        render("[a](/uri \"title\")"),
+        "<a href=\"/uri\" title=\"title\">a</a>"
+    );
+    assert_eq!(
+        render("[*a* b](<my page>)"),
+        "<a href=\"my%20page\"><em>a</em> b</a>"
+    );
+    assert_eq!(render("[a](\\(b\\))"), "<a href=\"(b)\">a</a>");
+    assert_eq!(render("[a](b c)"), "[a](b c)");
+    assert_eq!(
+        render("[a](b&amp;c?d=\\*)"),
+        "<a href=\"b&amp;c?d=*\">a</a>"
+    );
+    assert_eq!(
+        render("![a *b*](c.png)"),
+        "<img src=\"c.png\" alt=\"a b\" />"
+    );
+    assert_eq!(
+        render("![a ![b](c) [\"d\"](e)](f)"),
+        "<img src=\"f\" alt=\"a b &quot;d&quot;\" />"
+    );
+    // Links do not nest, and emphasis does not cross their brackets
+    assert_eq!(render("[a [b](c) d](e)"), "[a <a href=\"c\">b</a> d](e)");
+    assert_eq!(render("*[a*](b)"), "*<a href=\"b\">a*</a>");
+    assert_eq!(render("[a] (b) [c]"), "[a] (b) [c]");
+    assert_eq!(render("`[a](b)`"), "<code>[a](b)</code>");
+}
+
+#[test]
+fn md_links() {
+    let md_links: Extensions = Extensions {
+        md_links: true,
+        ..Extensions::default()
+    };
+    let render = |text: &str| render_inline(text, md_links, &mut Footnotes::default());
+    assert_eq!(
+        render("[a](intro.md) [b](../ref/api.md#usage)"),
+        "<a href=\"intro.html\">a</a> <a href=\"../ref/api.html#usage\">b</a>"
+    );
+    assert_eq!(
+        render("[a](https://example.com/x.md) [b](/abs.md) [c](pic.png) [d](#top)"),
+        "<a href=\"https://example.com/x.md\">a</a> <a href=\"/abs.md\">b</a> \
+         <a href=\"pic.png\">c</a> <a href=\"#top\">d</a>"
+    );
+    assert_eq!(
+        render("`[a](a.md)` <a title=\"[c](c.md)\">"),
+        "<code>[a](a.md)</code> <a title=\"[c](c.md)\">"
+    );
+}
+
+#[test]
+fn footnote_references() {
+    let mut footnotes: Footnotes = Footnotes::build(vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(
+        render_inline(
+            "x[^b] y[^A] z[^b] [^c] `[^a]`",
+            Extensions::default(),
+            &mut footnotes
+        ),
+        "x<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2\">2</a></sup> \
+         y<sup class=\"footnote-ref\"><a href=\"#fn-1\" id=\"fnref-1\">1</a></sup> \
+         z<sup class=\"footnote-ref\"><a href=\"#fn-2\" id=\"fnref-2-2\">2</a></sup> \
+         [^c] <code>[^a]</code>"
+    );
+}
+
+#[test]
+fn long_unmatched_input() {
+    // Each used to take seconds, from searching the rest of the text at every character
+    let inputs: [String; 5] = [
+        "a".repeat(80_000),
+        "<!-- ".repeat(20_000),
+        "a* ".repeat(20_000),
+        "[^".repeat(20_000),
+        "*a ".repeat(10_000) + &" a*".repeat(10_000),
+    ];
+    for input in inputs {
+        let start: std::time::Instant = std::time::Instant::now();
+        render(&input);
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(1),
+            "{:?}... took {:?}",
+            &input[..10],
+            start.elapsed()
+        );
+    }
+}
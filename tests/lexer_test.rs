@@ -0,0 +1,155 @@
+use std::borrow::Cow;
+use std::io::{BufReader, Cursor, Read};
+
+use md_parser::ast::run_ast;
+use md_parser::document::Document;
+use md_parser::lexer::{Lexer, Token, TokenType, lex, lex_iter, lex_lossless};
+
+const SAMPLE: &str = "# Title\n\nSome text\nHeader 1|Header 2\n---|---\n1|2\n\n```\ncode\n```\n";
+
+// Reader that never runs out of lines
+struct Endless;
+
+impl Read for Endless {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let line: &[u8] = b"more text\n";
+        let len: usize = line.len().min(buf.len());
+        buf[..len].copy_from_slice(&line[..len]);
+        Ok(len)
+    }
+}
+
+#[test]
+fn stream_matches_lex() {
+    let streamed: String = run_ast(Lexer::build(Cursor::new(SAMPLE))).to_string();
+    assert_eq!(streamed, run_ast(lex(SAMPLE)).to_string());
+}
+
+#[test]
+fn borrowed_tokens() {
+    // Tokens borrow from the text, one line at a time
+    let tokens: Vec<Token> = lex_iter(SAMPLE).collect();
+    assert_eq!(
+        run_ast(lex_iter(SAMPLE)).to_string(),
+        run_ast(lex(SAMPLE)).to_string()
+    );
+    assert!(
+        tokens
+            .iter()
+            .any(|t| matches!(t.value, Cow::Borrowed("Some text")))
+    );
+}
+
+#[test]
+fn crlf_line_endings() {
+    let streamed: String =
+        run_ast(Lexer::build(Cursor::new(SAMPLE.replace('\n', "\r\n")))).to_string();
+    assert_eq!(streamed, run_ast(lex(SAMPLE)).to_string());
+}
+
+#[test]
+fn lazy_tokens() {
+    let tokens: Vec<Token> = Lexer::build(BufReader::new(Endless)).take(3).collect();
+    assert_eq!(tokens.len(), 3);
+    assert!(tokens.iter().all(|t| t.value == "more text"));
+}
+
+#[test]
+fn read_error() {
+    let mut lexer = Lexer::build(Cursor::new(b"fine\n\xff\xfe\nnever read\n".to_vec()));
+    let tokens: Vec<Token> = lexer.by_ref().collect();
+    assert_eq!(tokens.len(), 1);
+    assert!(lexer.finish().is_err());
+}
+
+#[test]
+fn delimiter_row_alignment() {
+    let values =
+        |line: &str| -> Vec<String> { lex(line).iter().map(|t| t.value.to_string()).collect() };
+    assert_eq!(
+        values("|:---|:---:|---:|"),
+        ["table left center right |:---|:---:|---:|"]
+    );
+    assert_eq!(values("| :-- | --- |"), ["table left none | :-- | --- |"]);
+    assert_eq!(values("---|---"), ["table ---|---"]);
+    assert_eq!(values("|::--|"), ["|::--|"]);
+}
+
+#[test]
+fn atx_headings() {
+    let values =
+        |line: &str| -> Vec<String> { lex(line).iter().map(|t| t.value.to_string()).collect() };
+    assert_eq!(values("# foo"), ["h1", "foo"]);
+    assert_eq!(values("###### foo"), ["h6", "foo"]);
+    assert_eq!(values("####### foo"), ["####### foo"]);
+    assert_eq!(values("#5 bolt"), ["#5 bolt"]);
+    assert_eq!(values("## foo ##   "), ["h2", "foo"]);
+    assert_eq!(values("# foo#"), ["h1", "foo#"]);
+    assert_eq!(values("### ###"), ["h3", ""]);
+    assert_eq!(values("#"), ["h1", ""]);
+    assert_eq!(values("   # foo"), ["indent 3", "h1", "foo"]);
+    assert_eq!(values("    # foo"), ["indent 4", "# foo"]);
+}
+
+#[test]
+fn setext_underlines() {
+    let values =
+        |line: &str| -> Vec<String> { lex(line).iter().map(|t| t.value.to_string()).collect() };
+    assert_eq!(values("==="), ["h1 ==="]);
+    assert_eq!(values("--  "), ["h2 --"]);
+    assert_eq!(values("   ---"), ["indent 3", "h2 hr ---"]);
+    assert_eq!(values("    ==="), ["indent 4", "==="]);
+    assert_eq!(values("= ="), ["= ="]);
+}
+
+#[test]
+fn stream_front_matter() {
+    let source: &str = "---\ntitle: Hi\n---\n# Body\n";
+    let mut lexer = Lexer::build(Cursor::new(source));
+    let front_matter = lexer.front_matter().unwrap().unwrap();
+    assert_eq!(front_matter.raw, "title: Hi\n");
+    assert_eq!(
+        run_ast(lexer).to_string(),
+        run_ast(lex("# Body\n")).to_string()
+    );
+
+    // Without a closing fence the lines are markdown after all
+    let source: &str = "+++\ntitle = 1\n";
+    let mut lexer = Lexer::build(Cursor::new(source));
+    assert!(lexer.front_matter().unwrap().is_none());
+    assert_eq!(run_ast(lexer).to_string(), run_ast(lex(source)).to_string());
+
+    // Nor is there front matter if the closing fence is too far in
+    let source: String = format!("---\n{}---\n", "key: value\n".repeat(10_000));
+    let mut lexer = Lexer::build(Cursor::new(source.as_str()));
+    assert!(lexer.front_matter().unwrap().is_none());
+    assert!(Document::parse(&source).front_matter().is_none());
+    assert_eq!(
+        run_ast(lexer).to_string(),
+        run_ast(lex(&source)).to_string()
+    );
+
+    // Which stops an unclosed fence from buffering the rest of the input
+    let mut lexer = Lexer::build(BufReader::new(Cursor::new("---\n").chain(Endless)));
+    assert!(lexer.front_matter().unwrap().is_none());
+    assert_eq!(lexer.next().unwrap().value, "h2 hr ---");
+}
+
+#[test]
+fn lossless_trivia() {
+    let source: &str = "> ## Title ##\r\n- item  \n";
+    let tokens: Vec<Token> = lex_lossless(source);
+    let joined: String = tokens
+        .iter()
+        .filter(|t| matches!(t.token_type, TokenType::Literal | TokenType::Trivia))
+        .map(|t| t.value.as_ref())
+        .collect();
+    assert_eq!(joined, source);
+
+    let trivia: Vec<&str> = tokens
+        .iter()
+        .filter(|t| matches!(t.token_type, TokenType::Trivia))
+        .map(|t| t.value.as_ref())
+        .collect();
+    assert_eq!(trivia, ["> ## ", " ##\r\n", "- ", "\n"]);
+}
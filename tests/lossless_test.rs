@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex};
+
+use md_parser::ast::{Content, Node, Tree, lock, run_ast};
+use md_parser::document::Document;
+use md_parser::lexer::lex;
+
+const SAMPLE: &str = "---\ntitle: Links\n---\n\
+                      Heading  ##\r\n=====\r\n\r\n\
+                      Read the [guide](old.md) first,  \n\tthen [more](old.md#top).\n\n\
+                      > * a [quote](old.md)\n>   continued\n\n\
+                      1)  one\n   ```  rust\n   let x = 1;   \n   ```\n\n\
+                      | Page | Link |\n|:--|--|\n|  home |[old](old.md)|  extra |\n\n\
+                      Note[^n].\n\n[^n]:   See [here](old.md).\n";
+
+// Applies <edit> to the text of every leaf, footnote definitions included
+fn edit_leaves(tree: &Tree, edit: &dyn Fn(&str) -> String) {
+    fn helper(node: &Arc<Mutex<Node>>, edit: &dyn Fn(&str) -> String) {
+        let mut node = lock(node);
+        match node.literal().map(edit) {
+            Some(text) => node.set_value(Content::Inline(text.into())),
+            None => {
+                for child in node.children().to_vec() {
+                    helper(&child, edit);
+                }
+            }
+        }
+    }
+    helper(&tree.root(), edit);
+    for definition in tree.footnote_definitions() {
+        helper(&definition, edit);
+    }
+}
+
+#[test]
+fn round_trip() {
+    let tree: Tree = Document::parse(SAMPLE).lossless_tree();
+    assert_eq!(tree.source().unwrap(), SAMPLE);
+    // The html is the same as without trivia
+    assert_eq!(tree.to_string(), Document::parse(SAMPLE).tree().to_string());
+}
+
+#[test]
+fn rename_link_target() {
+    let tree: Tree = Document::parse(SAMPLE).lossless_tree();
+    edit_leaves(&tree, &|text: &str| text.replace("old.md", "new.md"));
+    assert_eq!(tree.source().unwrap(), SAMPLE.replace("old.md", "new.md"));
+}
+
+#[test]
+fn edited_lines() {
+    let source: &str = "* first\n  second\n\n|a|b|\n|-|-|\n|1|2|\n";
+    let tree: Tree = Document::parse(source).lossless_tree();
+    edit_leaves(&tree, &|text: &str| match text {
+        "first\nsecond" => "first\nsecond\nthird".to_string(),
+        "2" => "x|y".to_string(),
+        _ => text.to_string(),
+    });
+    // Added lines go after the last one; pipes in cells are escaped
+    assert_eq!(
+        tree.source().unwrap(),
+        "* first\n  second\nthird\n\n|a|b|\n|-|-|\n|1|x\\|y|\n"
+    );
+}
+
+#[test]
+fn header_after_setext_heading() {
+    // Only the last line of the heading becomes the table header
+    let source: &str = "a|b\nc|d\n--\n-|-\n";
+    assert_eq!(
+        Document::parse(source).lossless_tree().source().unwrap(),
+        source
+    );
+}
+
+#[test]
+fn delimiter_rows_as_text() {
+    // Rows and paragraph text that the lexer read as delimiter rows or underlines
+    let source: &str = "a|b\n-|-\n1|2\n :-|- \n-- \n> --|:-\n\n |-|-| \n\n  -- \n";
+    let tree: Tree = Document::parse(source).lossless_tree();
+    assert_eq!(tree.source().unwrap(), source);
+    edit_leaves(&tree, &|text: &str| text.replace('-', "+"));
+    assert_eq!(
+        tree.source().unwrap(),
+        "a|b\n-|-\n1|2\n :+|+ \n++ \n> ++|:+\n\n |+|+| \n\n  ++ \n"
+    );
+}
+
+#[test]
+fn only_lossless_trees() {
+    assert!(run_ast(lex("# Title\n")).source().is_none());
+    assert_eq!(
+        Document::parse("").lossless_tree().source().as_deref(),
+        Some("")
+    );
+}
+
+#[test]
+fn long_paragraph_and_code() {
+    // Each line used to be found by counting through the text before it
+    let source: String = "word\n".repeat(40_000) + "\n```\n" + &"code\n".repeat(40_000) + "```\n";
+    let start: std::time::Instant = std::time::Instant::now();
+    let tree: Tree = Document::parse(&source).lossless_tree();
+    assert_eq!(tree.source().unwrap(), source);
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+}
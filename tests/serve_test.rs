@@ -0,0 +1,142 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::thread;
+
+use common::scratch;
+use md_parser::inline::Extensions;
+use md_parser::serve::{Response, respond, serve};
+
+fn body(response: &Response) -> String {
+    String::from_utf8_lossy(&response.body).into_owned()
+}
+
+#[test]
+fn render_markdown() {
+    let root: PathBuf = scratch("render_markdown");
+    fs::create_dir_all(root.join("guide")).unwrap();
+    fs::write(root.join("index.md"), "Home\n===\n").unwrap();
+    fs::write(root.join("guide/intro.md"), "Intro text\n").unwrap();
+
+    let index: Response = respond(&root, "/", Extensions::default());
+    assert_eq!(index.status, "200 OK");
+    assert!(body(&index).contains("<h1 id=\"home\">Home</h1>"));
+    assert!(body(&index).contains("EventSource(\"/__reload\")"));
+
+    let page: Response = respond(&root, "/guide/intro.md", Extensions::default());
+    assert!(body(&page).contains("<p>Intro text</p>"));
+    // Links rewritten by batch mode still resolve
+    let page: Response = respond(&root, "/guide/intro.html?x=1", Extensions::default());
+    assert!(body(&page).contains("<p>Intro text</p>"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn directories_and_assets() {
+    let root: PathBuf = scratch("directories_and_assets");
+    fs::create_dir_all(root.join("img dir")).unwrap();
+    fs::write(root.join("img dir/logo.png"), [1u8, 2, 3]).unwrap();
+
+    let redirect: Response = respond(&root, "/img%20dir", Extensions::default());
+    assert_eq!(redirect.status, "301 Moved Permanently");
+
+    let listing: Response = respond(&root, "/img%20dir/", Extensions::default());
+    assert!(body(&listing).contains("<a href=\"logo.png\">logo.png</a>"));
+
+    let asset: Response = respond(&root, "/img%20dir/logo.png", Extensions::default());
+    assert_eq!(asset.body, [1u8, 2, 3]);
+    assert!(
+        asset
+            .headers
+            .contains(&("Content-Type", "image/png".to_string()))
+    );
+
+    assert_eq!(
+        respond(&root, "/missing.md", Extensions::default()).status,
+        "404 Not Found"
+    );
+    assert_eq!(
+        respond(&root, "/../etc/passwd", Extensions::default()).status,
+        "404 Not Found"
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn redirect_keeps_query() {
+    let root: PathBuf = scratch("redirect_keeps_query");
+    fs::create_dir_all(root.join("docs")).unwrap();
+
+    let redirect: Response = respond(&root, "/docs?page=2", Extensions::default());
+    assert_eq!(redirect.status, "301 Moved Permanently");
+    assert!(
+        redirect
+            .headers
+            .contains(&("Location", "/docs/?page=2".to_string()))
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn listing_is_escaped() {
+    let root: PathBuf = scratch("listing_is_escaped");
+    fs::create_dir_all(root.join("<b>")).unwrap();
+    fs::write(root.join("<b>/x\"><script>.png"), [1u8]).unwrap();
+
+    let listing: String = body(&respond(&root, "/<b>/", Extensions::default()));
+    assert!(listing.contains("<h1>/&lt;b&gt;/</h1>"));
+    assert!(listing.contains("<a href=\"x%22%3E%3Cscript%3E.png\">x\"&gt;&lt;script&gt;.png</a>"));
+    assert!(!listing.contains("<script>.png"));
+
+    // Each link leads back to its file, whatever the name holds
+    fs::create_dir_all(root.join("a b")).unwrap();
+    fs::write(root.join("a b/c:d?#%.png"), [1u8]).unwrap();
+    let listing: String = body(&respond(&root, "/a%20b/", Extensions::default()));
+    assert!(listing.contains("<a href=\"c%3Ad%3F%23%25.png\">c:d?#%.png</a>"));
+    let listing: String = body(&respond(&root, "/", Extensions::default()));
+    assert!(listing.contains("<a href=\"a%20b/\">a b/</a>"));
+    assert_eq!(
+        respond(&root, "/a%20b/c%3Ad%3F%23%25.png", Extensions::default()).body,
+        [1u8]
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn answer_over_http() {
+    let root: PathBuf = scratch("answer_over_http");
+    fs::write(root.join("index.md"), "# Served\n").unwrap();
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port: u16 = listener.local_addr().unwrap().port();
+    let served: PathBuf = root.clone();
+    thread::spawn(move || {
+        serve(
+            listener,
+            &served,
+            Arc::new(AtomicUsize::new(0)),
+            Extensions::default(),
+        )
+    });
+
+    let mut stream: TcpStream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut reply: String = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+
+    assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(reply.contains("Served</h1>"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,101 @@
+use md_parser::ast::{Tree, run_ast};
+use md_parser::document::Document;
+use md_parser::lexer::lex;
+use md_parser::toc::{TocEntry, toc_markdown};
+
+const SAMPLE: &str = "# Guide\n\nIntro\n\n## Install\n### From source\n\
+                      Usage {#use}\n-----\n#### Deep\n# Appendix\n";
+
+#[test]
+fn outline() {
+    let toc: Vec<TocEntry> = run_ast(lex(SAMPLE)).toc(1, 6);
+    assert_eq!(toc.len(), 2);
+    let guide: &TocEntry = &toc[0];
+    assert_eq!(
+        (guide.text.as_str(), guide.level, guide.lines.clone()),
+        ("Guide", 1, 0..1)
+    );
+
+    let sections: Vec<(&str, &str, usize)> = guide
+        .children
+        .iter()
+        .map(|entry| {
+            (
+                entry.text.as_str(),
+                entry.slug.as_str(),
+                entry.children.len(),
+            )
+        })
+        .collect();
+    assert_eq!(sections, [("Install", "install", 1), ("Usage", "use", 1)]);
+    assert_eq!(guide.children[1].lines, 6..8);
+    assert_eq!(guide.children[1].children[0].level, 4);
+    assert_eq!(toc[1].slug, "appendix");
+}
+
+#[test]
+fn outline_levels() {
+    let toc: Vec<TocEntry> = run_ast(lex(SAMPLE)).toc(2, 3);
+    let texts: Vec<&str> = toc.iter().map(|entry| entry.text.as_str()).collect();
+    assert_eq!(texts, ["Install", "Usage"]);
+    assert_eq!(toc[0].children[0].text, "From source");
+    assert!(toc[1].children.is_empty());
+}
+
+#[test]
+fn outline_as_markdown() {
+    assert_eq!(
+        toc_markdown(&run_ast(lex(SAMPLE)).toc(1, 2)),
+        "- [Guide](#guide)\n  - [Install](#install)\n  - [Usage](#use)\n- [Appendix](#appendix)\n"
+    );
+}
+
+#[test]
+fn outline_after_front_matter() {
+    let toc: Vec<TocEntry> = Document::parse("---\ntitle: x\n---\n# Top\n")
+        .tree()
+        .toc(1, 6);
+    assert_eq!(toc[0].lines, 3..4);
+}
+
+#[test]
+fn toc_marker() {
+    for marker in ["[[_TOC_]]", "<!-- toc -->"] {
+        let source: String = format!("# Title\n\n{}\n\n## *Part* one\n", marker);
+        let output: Tree = run_ast(lex(&source));
+        assert_eq!(
+            output.to_string(),
+            "<html>
+    <h1 id=\"title\">Title</h1>
+    <ul class=\"toc\">
+        <li>
+            <a href=\"#title\">Title</a>
+            <ul>
+                <li>
+                    <a href=\"#part-one\"><em>Part</em> one</a>
+                </li>
+            </ul>
+        </li>
+    </ul>
+    <h2 id=\"part-one\"><em>Part</em> one</h2>
+</html>"
+        );
+    }
+}
+
+#[test]
+fn only_first_marker() {
+    let output: Tree = run_ast(lex("# A\n\n[[_TOC_]]\n\n[[_TOC_]]\n"));
+    assert_eq!(
+        output.to_string(),
+        "<html>
+    <h1 id=\"a\">A</h1>
+    <ul class=\"toc\">
+        <li>
+            <a href=\"#a\">A</a>
+        </li>
+    </ul>
+    <p>[[<em>TOC</em>]]</p>
+</html>"
+    );
+}
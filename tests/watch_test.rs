@@ -0,0 +1,60 @@
+mod common;
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use common::scratch;
+use md_parser::watch::Watcher;
+
+fn touch(path: &Path, secs: u64) {
+    let mtime: SystemTime = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+}
+
+#[test]
+fn detect_changes() {
+    let root: PathBuf = scratch("detect_changes");
+    let a: PathBuf = root.join("a.md");
+    let b: PathBuf = root.join("b.md");
+    fs::write(&a, "a").unwrap();
+    fs::write(&b, "b").unwrap();
+    touch(&a, 1_000);
+    touch(&b, 1_000);
+
+    let mut watcher: Watcher = Watcher::build();
+    let paths: [&Path; 2] = [&a, &b];
+    assert_eq!(watcher.changed(&paths), vec![a.clone(), b.clone()]);
+    assert!(watcher.changed(&paths).is_empty());
+
+    touch(&b, 2_000);
+    assert_eq!(watcher.changed(&paths), vec![b.clone()]);
+    assert!(watcher.changed(&paths).is_empty());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn deleted_and_recreated() {
+    let root: PathBuf = scratch("deleted_and_recreated");
+    let a: PathBuf = root.join("a.md");
+    fs::write(&a, "a").unwrap();
+    touch(&a, 1_000);
+
+    let mut watcher: Watcher = Watcher::build();
+    assert_eq!(watcher.changed(&[&a]).len(), 1);
+
+    fs::remove_file(&a).unwrap();
+    assert!(watcher.changed(&[&a]).is_empty());
+
+    fs::write(&a, "a again").unwrap();
+    touch(&a, 1_000);
+    assert_eq!(watcher.changed(&[&a]), vec![a.clone()]);
+
+    fs::remove_dir_all(&root).unwrap();
+}